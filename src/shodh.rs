@@ -1,10 +1,32 @@
 use std::env;
 use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::collections::BinaryHeap;
+use std::process::{Command, Stdio};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rayon::prelude::*;
+use unicode_normalization::UnicodeNormalization;
 
 const VERSION: &str = "0.1.0";
+// Below this many candidates, rayon's setup is assumed to cost more than it
+// saves. Not benchmarked across machines; see bench_parallel_threshold.
+const DEFAULT_PARALLEL_THRESHOLD: usize = 2048;
+// Parallel tasks per rayon thread when --parallel-chunk-size is left on auto.
+// A guess at balance vs overhead, not a tuned value; see
+// bench_parallel_chunk_size.
+const PARALLEL_TASKS_PER_THREAD: usize = 8;
+// Hint on stderr once matches outnumber the shown results this many times over
+const LIMIT_WARNING_RATIO: usize = 10;
+// Top two scores this close count as a tie for --fail-if-ambiguous
+const DEFAULT_AMBIGUITY_MARGIN: i32 = 2;
+// Stop walking after this many entries unless raised with --walk-max-entries
+const DEFAULT_WALK_MAX_ENTRIES: usize = 1_000_000;
+// Lines of --preview output shown under each result
+const PREVIEW_MAX_LINES: usize = 5;
+// How often --progress-json reports during the walk
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CaseSensitivity {
@@ -12,29 +34,265 @@ enum CaseSensitivity {
     Insensitive,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Normalization {
+    Nfc,
+    Nfd,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryTransform {
+    None,
+    Snake,
+    Camel,
+    Kebab,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Human,
+    Path,
+    Editor,
+    Nul,
+    Json,
+    Jsonl,
+    Shell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonField {
+    Path,
+    Score,
+    Type,
+    Hidden,
+    Change,
+    Rank,
+    Lines,
+    Compare,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ranking {
+    Alignment,
+    Density,
+}
+
+// Knobs for the scorer, passed down as one value
+#[derive(Debug, Clone, Copy)]
+struct ScoringConfig {
+    ranking: Ranking,
+    // Boost for names equal to the query up to case and extension
+    near_exact_boost: i32,
+    // Ceiling for the summed score, so boosted names can tie with plain ones
+    cap: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScoreTransform {
+    None,
+    Log,
+    Rank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TieBreak {
+    Path,
+    Mtime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Theme {
+    Default,
+    MatchedOnly,
+}
+
 #[derive(Debug)]
 struct Config {
     query: String,
     root: String,
     num: usize,
+    display_min_score: Option<i32>,
+    sample: Option<usize>,
+    seed: Option<u64>,
     files_only: bool,
     dirs_only: bool,
+    // Some(false): only files without an extension, Some(true): only files with one
+    has_ext: Option<bool>,
     case: CaseSensitivity,
+    normalize: Normalization,
+    transform: QueryTransform,
+    scoring: ScoringConfig,
+    tiebreak: TieBreak,
+    // Added to a match's score when its path contains the substring
+    path_boosts: Vec<(String, i32)>,
     parallel: bool,
+    parallel_threshold: usize,
+    // Fewest candidates per rayon task; None picks one from the input size
+    parallel_chunk_size: Option<usize>,
+    strict_walk: bool,
+    sorted_walk: bool,
+    resume_from: Option<PathBuf>,
+    walk_max_entries: usize,
+    limit_memory: Option<usize>,
+    include_git: bool,
+    include_special: bool,
+    // Only entries owned by this uid / gid (Unix)
+    owner: Option<u32>,
+    group: Option<u32>,
+    follow_symlinks: bool,
+    // Also score symlinks by their target's file name
+    match_target_name: bool,
+    same_filesystem: bool,
+    allow_mounts: Vec<PathBuf>,
+    dedupe_hardlinks: bool,
+    // Don't descend into directories whose name is an exact or prefix match
+    prune: bool,
+    format: OutputFormat,
+    theme: Theme,
+    score_transform: ScoreTransform,
+    json_fields: Vec<JsonField>,
+    mark_hidden: bool,
+    show_rank: bool,
+    // Count the lines of file results, shown as a tag and a JSON field
+    line_counts: bool,
+    // Print the shown results' common directory once and paths relative to it
+    collapse_prefix: bool,
+    // Shell-quote the paths in human output so they paste safely
+    quote: bool,
+    // Append a separator to directories in human and path output, like `ls -p`
+    dir_slash: bool,
+    // Paths of a previous --format json/jsonl run, best first
+    diff_against: Option<Vec<String>>,
+    preview: Option<String>,
+    // Command that gets the result paths on stdin instead of stdout
+    pipe: Option<String>,
+    line_buffered: bool,
+    // Plain 8-color output without bold or dim, for limited terminals
+    simple_color: bool,
+    show_counts: bool,
+    limit_warning: bool,
+    list_filters: bool,
+    nth: Option<usize>,
+    // Open the top (or --nth) result in $VISUAL/$EDITOR instead of printing
+    open: bool,
+    fail_if_ambiguous: bool,
+    ambiguity_margin: i32,
+    score_debug: bool,
+    compare: Option<String>,
+    calibrate: bool,
+    // Two paths to score side by side for --why-above
+    why_above: Option<(PathBuf, PathBuf)>,
+    match_report: bool,
+    progress_json: bool,
+    stats_json: bool,
+    min_entries: Option<usize>,
+    max_entries: Option<usize>,
+    max_gap: Option<usize>,
+    min_components: Option<usize>,
+    max_components: Option<usize>,
+    suffix: bool,
+    not_exact: bool,
+    above_median: bool,
+    // List exact, near-exact and prefix matches before any fuzzy ones
+    exact_first: bool,
+    // At most this many shown results per distinct score
+    per_tier: Option<usize>,
+    scope_dir: Option<String>,
     help: bool,
     version: bool,
 }
 
+// Parse the value following a numeric flag at args[i]
+fn parse_number<T: std::str::FromStr>(args: &[String], i: usize, flag: &str) -> Result<T, String> {
+    let value = args.get(i + 1).ok_or_else(|| format!("Expected a number after {}", flag))?;
+    value.parse().map_err(|_| format!("Invalid number for {}", flag))
+}
+
 impl Config {
     fn from_args() -> Result<Self, String> {
-        let args: Vec<String> = env::args().collect();
+        Self::parse(env::args().collect())
+    }
+
+    // `args[0]` is the program name, as in `env::args`
+    fn parse(args: Vec<String>) -> Result<Self, String> {
         let mut query = None;
         let mut root = None;
         let mut num = 10;
+        let mut display_min_score = None;
+        let mut sample = None;
+        let mut seed = None;
         let mut files_only = false;
         let mut dirs_only = false;
-        let mut case = CaseSensitivity::Insensitive;
+        let mut has_ext = None;
+        let mut case = None;
+        let mut fs_case = false;
+        let mut verbatim = false;
+        let mut normalize = Normalization::Nfc;
+        let mut transform = QueryTransform::None;
+        let mut ranking = Ranking::Alignment;
+        let mut near_exact_boost = DEFAULT_NEAR_EXACT_BOOST;
+        let mut cap = None;
+        let mut tiebreak = TieBreak::Path;
+        let mut path_boosts = Vec::new();
         let mut parallel = true;
+        let mut parallel_threshold = DEFAULT_PARALLEL_THRESHOLD;
+        let mut parallel_chunk_size = None;
+        let mut strict_walk = false;
+        let mut sorted_walk = false;
+        let mut resume_from = None;
+        let mut walk_max_entries = DEFAULT_WALK_MAX_ENTRIES;
+        let mut limit_memory = None;
+        let mut include_git = false;
+        let mut include_special = false;
+        let mut owner = None;
+        let mut group = None;
+        let mut follow_symlinks = false;
+        let mut match_target_name = false;
+        let mut same_filesystem = false;
+        let mut dedupe_hardlinks = false;
+        let mut prune = false;
+        let mut allow_mounts = Vec::new();
+        let mut format = OutputFormat::Human;
+        let mut theme = Theme::Default;
+        let mut score_transform = ScoreTransform::None;
+        let mut json_fields = None;
+        let mut mark_hidden = false;
+        let mut line_counts = false;
+        let mut dir_slash = true;
+        let mut collapse_prefix = false;
+        let mut quote = false;
+        let mut show_rank = false;
+        let mut diff_against = None;
+        let mut preview = None;
+        let mut pipe = None;
+        let mut line_buffered = false;
+        let mut simple_color = env::var("TERM").is_ok_and(|term| term == "dumb");
+        let mut show_counts = false;
+        let mut limit_warning = true;
+        let mut list_filters = false;
+        let mut nth = None;
+        let mut open = false;
+        let mut fail_if_ambiguous = false;
+        let mut ambiguity_margin = DEFAULT_AMBIGUITY_MARGIN;
+        let mut score_debug = false;
+        let mut compare = None;
+        let mut calibrate = false;
+        let mut why_above = None;
+        let mut match_report = false;
+        let mut progress_json = false;
+        let mut stats_json = false;
+        let mut min_entries = None;
+        let mut max_entries = None;
+        let mut max_gap = None;
+        let mut min_components = None;
+        let mut max_components = None;
+        let mut suffix = false;
+        let mut not_exact = false;
+        let mut above_median = false;
+        let mut exact_first = false;
+        let mut per_tier = None;
+        let mut scope_dir = None;
         let mut help = false;
         let mut version = false;
         let mut i = 1;
@@ -42,18 +300,295 @@ impl Config {
             match args[i].as_str() {
                 "-h" | "--help" => { help = true; i += 1; },
                 "-v" | "--version" => { version = true; i += 1; },
-                "-n" | "--num" => {
+                "-n" | "--num" => { num = parse_number(&args, i, "--num")?; i += 2; },
+                "--display-min-score" => { display_min_score = Some(parse_number(&args, i, "--display-min-score")?); i += 2; },
+                "--sample" => { sample = Some(parse_number(&args, i, "--sample")?); i += 2; },
+                "--seed" => { seed = Some(parse_number(&args, i, "--seed")?); i += 2; },
+                "--files-only" => { files_only = true; i += 1; },
+                "--dirs-only" => { dirs_only = true; i += 1; },
+                "--no-ext" => { has_ext = Some(false); i += 1; },
+                "--require-ext" => { has_ext = Some(true); i += 1; },
+                "-i" | "--ignore-case" => { case = Some(CaseSensitivity::Insensitive); i += 1; },
+                "-s" | "--case-sensitive" => { case = Some(CaseSensitivity::Sensitive); i += 1; },
+                "--fs-case" => { fs_case = true; i += 1; },
+                "--verbatim" => { verbatim = true; i += 1; },
+                "--normalize" => {
                     if i + 1 >= args.len() {
-                        return Err("Expected a number after --num".to_string());
+                        return Err("Expected nfc, nfd or none after --normalize".to_string());
                     }
-                    num = args[i + 1].parse().map_err(|_| "Invalid number for --num".to_string())?;
+                    normalize = match args[i + 1].as_str() {
+                        "nfc" => Normalization::Nfc,
+                        "nfd" => Normalization::Nfd,
+                        "none" => Normalization::None,
+                        other => return Err(format!("Invalid value for --normalize: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--query-transform" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected none, snake, camel or kebab after --query-transform".to_string());
+                    }
+                    transform = match args[i + 1].as_str() {
+                        "none" => QueryTransform::None,
+                        "snake" => QueryTransform::Snake,
+                        "camel" => QueryTransform::Camel,
+                        "kebab" => QueryTransform::Kebab,
+                        other => return Err(format!("Invalid value for --query-transform: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--rank" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected alignment or density after --rank".to_string());
+                    }
+                    ranking = match args[i + 1].as_str() {
+                        "alignment" => Ranking::Alignment,
+                        "density" => Ranking::Density,
+                        other => return Err(format!("Invalid value for --rank: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--near-exact-boost" => {
+                    near_exact_boost = parse_number(&args, i, "--near-exact-boost")?;
+                    // Between the prefix boost it replaces and the exact boost
+                    if !(PREFIX_BOOST..EXACT_BOOST).contains(&near_exact_boost) {
+                        return Err(format!("--near-exact-boost must be at least the prefix boost ({}) and below the exact boost ({})",
+                            PREFIX_BOOST, EXACT_BOOST));
+                    }
+                    i += 2;
+                },
+                "--cap-score" => {
+                    let n: i32 = parse_number(&args, i, "--cap-score")?;
+                    if n < 1 {
+                        return Err("--cap-score must be at least 1".to_string());
+                    }
+                    cap = Some(n);
+                    i += 2;
+                },
+                "--boost-path" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected <substr>:<N> after --boost-path".to_string());
+                    }
+                    // The last colon splits, so the substring may contain colons
+                    let (part, boost) = args[i + 1].rsplit_once(':')
+                        .filter(|(part, _)| !part.is_empty())
+                        .ok_or_else(|| format!("Invalid value for --boost-path: {} (expected <substr>:<N>)", args[i + 1]))?;
+                    let boost = boost.parse().map_err(|_| format!("Invalid boost for --boost-path: {}", boost))?;
+                    path_boosts.push((part.to_string(), boost));
+                    i += 2;
+                },
+                "--tiebreak" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected path or mtime after --tiebreak".to_string());
+                    }
+                    tiebreak = match args[i + 1].as_str() {
+                        "path" => TieBreak::Path,
+                        "mtime" => TieBreak::Mtime,
+                        other => return Err(format!("Invalid value for --tiebreak: {}", other)),
+                    };
                     i += 2;
                 },
-                "--files-only" => { files_only = true; i += 1; },
-                "--dirs-only" => { dirs_only = true; i += 1; },
-                "-i" | "--ignore-case" => { case = CaseSensitivity::Insensitive; i += 1; },
-                "-s" | "--case-sensitive" => { case = CaseSensitivity::Sensitive; i += 1; },
                 "--no-parallel" => { parallel = false; i += 1; },
+                "--parallel-threshold" => { parallel_threshold = parse_number(&args, i, "--parallel-threshold")?; i += 2; },
+                "--parallel-chunk-size" => {
+                    let n: usize = parse_number(&args, i, "--parallel-chunk-size")?;
+                    if n == 0 {
+                        return Err("--parallel-chunk-size must be at least 1".to_string());
+                    }
+                    parallel_chunk_size = Some(n);
+                    i += 2;
+                },
+                "--strict-walk" | "--abort-on-error" => { strict_walk = true; i += 1; },
+                "--sorted-walk" | "--deterministic-walk" => { sorted_walk = true; i += 1; },
+                "--walk-max-entries" => { walk_max_entries = parse_number(&args, i, "--walk-max-entries")?; i += 2; },
+                "--limit-memory" => {
+                    let mb: usize = parse_number(&args, i, "--limit-memory")?;
+                    limit_memory = Some(mb.saturating_mul(1024 * 1024));
+                    i += 2;
+                },
+                "--include-git" => { include_git = true; i += 1; },
+                "--include-special" => { include_special = true; i += 1; },
+                "--owner" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a user name or uid after --owner".to_string());
+                    }
+                    owner = Some(resolve_id(&args[i + 1], "/etc/passwd", "--owner")?);
+                    i += 2;
+                },
+                "--group" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a group name or gid after --group".to_string());
+                    }
+                    group = Some(resolve_id(&args[i + 1], "/etc/group", "--group")?);
+                    i += 2;
+                },
+                "-L" | "--follow-symlinks" => { follow_symlinks = true; i += 1; },
+                "--match-target-name" => { match_target_name = true; i += 1; },
+                "-x" | "--same-filesystem" => { same_filesystem = true; i += 1; },
+                "--dedupe-hardlinks" => { dedupe_hardlinks = true; i += 1; },
+                "--prune" => { prune = true; i += 1; },
+                "--allow-mount" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a path after --allow-mount".to_string());
+                    }
+                    // Canonical so it compares equal however the walk reaches it
+                    let mount = PathBuf::from(&args[i + 1]);
+                    allow_mounts.push(fs::canonicalize(&mount).unwrap_or(mount));
+                    i += 2;
+                },
+                "--resume-from" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a path after --resume-from".to_string());
+                    }
+                    resume_from = Some(PathBuf::from(&args[i + 1]));
+                    i += 2;
+                },
+                "--format" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected human, path, editor, nul, json, jsonl or shell after --format".to_string());
+                    }
+                    format = match args[i + 1].as_str() {
+                        "human" => OutputFormat::Human,
+                        "path" => OutputFormat::Path,
+                        "editor" => OutputFormat::Editor,
+                        "nul" => OutputFormat::Nul,
+                        "json" => OutputFormat::Json,
+                        "jsonl" => OutputFormat::Jsonl,
+                        "shell" => OutputFormat::Shell,
+                        other => return Err(format!("Invalid value for --format: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--editor-format" => { format = OutputFormat::Editor; i += 1; },
+                "-0" | "--print0" => { format = OutputFormat::Nul; i += 1; },
+                "--json-fields" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a comma-separated field list after --json-fields".to_string());
+                    }
+                    json_fields = Some(args[i + 1].split(',').map(|field| match field.trim() {
+                        "path" => Ok(JsonField::Path),
+                        "score" => Ok(JsonField::Score),
+                        "type" => Ok(JsonField::Type),
+                        "hidden" => Ok(JsonField::Hidden),
+                        "change" => Ok(JsonField::Change),
+                        "rank" => Ok(JsonField::Rank),
+                        "lines" => Ok(JsonField::Lines),
+                        "compare" => Ok(JsonField::Compare),
+                        other => Err(format!("Unknown field for --json-fields: {}", other)),
+                    }).collect::<Result<_, _>>()?);
+                    i += 2;
+                },
+                "--mark-hidden" => { mark_hidden = true; i += 1; },
+                "--no-slash" => { dir_slash = false; i += 1; },
+                "--collapse-prefix" => { collapse_prefix = true; i += 1; },
+                "--quote" => { quote = true; i += 1; },
+                "--show-rank" => { show_rank = true; i += 1; },
+                "--line-counts" => { line_counts = true; i += 1; },
+                "--diff-against" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a JSON results file after --diff-against".to_string());
+                    }
+                    let file = &args[i + 1];
+                    let text = fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+                    diff_against = Some(read_result_paths(&text).map_err(|e| format!("{}: {}", file, e))?);
+                    i += 2;
+                },
+                "--preview" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a command after --preview".to_string());
+                    }
+                    preview = Some(args[i + 1].clone());
+                    i += 2;
+                },
+                "--pipe" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a command after --pipe".to_string());
+                    }
+                    pipe = Some(args[i + 1].clone());
+                    i += 2;
+                },
+                "--score-transform" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected none, log or rank after --score-transform".to_string());
+                    }
+                    score_transform = match args[i + 1].as_str() {
+                        "none" => ScoreTransform::None,
+                        "log" => ScoreTransform::Log,
+                        "rank" => ScoreTransform::Rank,
+                        other => return Err(format!("Invalid value for --score-transform: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--theme" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected default or matched-only after --theme".to_string());
+                    }
+                    theme = match args[i + 1].as_str() {
+                        "default" => Theme::Default,
+                        "matched-only" => Theme::MatchedOnly,
+                        other => return Err(format!("Invalid value for --theme: {}", other)),
+                    };
+                    i += 2;
+                },
+                "--line-buffered" => { line_buffered = true; i += 1; },
+                "--simple-color" => { simple_color = true; i += 1; },
+                "--show-counts" => { show_counts = true; i += 1; },
+                "--no-limit-warning" => { limit_warning = false; i += 1; },
+                "--list-filters" => { list_filters = true; i += 1; },
+                "--score-debug" => { score_debug = true; i += 1; },
+                "--calibrate" => { calibrate = true; i += 1; },
+                "--why-above" => {
+                    if i + 2 >= args.len() {
+                        return Err("Expected two paths after --why-above".to_string());
+                    }
+                    why_above = Some((PathBuf::from(&args[i + 1]), PathBuf::from(&args[i + 2])));
+                    i += 3;
+                },
+                "--match-report" => { match_report = true; i += 1; },
+                "--progress-json" => { progress_json = true; i += 1; },
+                "--compare" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a second query after --compare".to_string());
+                    }
+                    compare = Some(args[i + 1].clone());
+                    i += 2;
+                },
+                "--stats-json" => { stats_json = true; i += 1; },
+                "--min-entries" => { min_entries = Some(parse_number(&args, i, "--min-entries")?); i += 2; },
+                "--max-entries" => { max_entries = Some(parse_number(&args, i, "--max-entries")?); i += 2; },
+                "--max-gap" => { max_gap = Some(parse_number(&args, i, "--max-gap")?); i += 2; },
+                "--min-components" => { min_components = Some(parse_number(&args, i, "--min-components")?); i += 2; },
+                "--max-components" => { max_components = Some(parse_number(&args, i, "--max-components")?); i += 2; },
+                "--suffix" | "--anchored-end" => { suffix = true; i += 1; },
+                "--not-exact" => { not_exact = true; i += 1; },
+                "--above-median" => { above_median = true; i += 1; },
+                "--exact-first" => { exact_first = true; i += 1; },
+                "--per-tier" => {
+                    let n: usize = parse_number(&args, i, "--per-tier")?;
+                    if n == 0 {
+                        return Err("--per-tier must be at least 1".to_string());
+                    }
+                    per_tier = Some(n);
+                    i += 2;
+                },
+                "--scope-dir" => {
+                    if i + 1 >= args.len() {
+                        return Err("Expected a query after --scope-dir".to_string());
+                    }
+                    scope_dir = Some(args[i + 1].clone());
+                    i += 2;
+                },
+                "--fail-if-ambiguous" => { fail_if_ambiguous = true; i += 1; },
+                "--ambiguity-margin" => { ambiguity_margin = parse_number(&args, i, "--ambiguity-margin")?; i += 2; },
+                "--nth" => {
+                    let n: usize = parse_number(&args, i, "--nth")?;
+                    if n == 0 {
+                        return Err("--nth is 1-based and must be at least 1".to_string());
+                    }
+                    nth = Some(n);
+                    i += 2;
+                },
+                "--open" => { open = true; i += 1; },
                 _ => {
                     if query.is_none() {
                         query = Some(args[i].clone());
@@ -67,46 +602,305 @@ impl Config {
                 }
             }
         }
-        if help || version {
-            return Ok(Config {
-                query: String::new(),
-                root: String::new(),
-                num,
-                files_only,
-                dirs_only,
-                case,
-                parallel,
-                help,
-                version,
-            });
+        // Resuming is only meaningful in a reproducible walk order
+        if resume_from.is_some() {
+            sorted_walk = true;
+        }
+        // Entry counts only exist for directories
+        if min_entries.is_some() || max_entries.is_some() {
+            dirs_only = true;
+        }
+        // Pasted queries often carry stray spaces; --verbatim keeps them
+        let tidy = |q: String| if verbatim { q } else { collapse_whitespace(&q) };
+        let compare = compare.map(tidy);
+        let (query, root) = if help || version {
+            (String::new(), String::new())
+        } else if list_filters {
+            (query.map(tidy).unwrap_or_default(), root.unwrap_or_else(|| ".".to_string()))
+        } else {
+            let query = tidy(query.ok_or("Missing query argument. Use -h for help.")?);
+            if query.is_empty() {
+                return Err("Query is empty. Use -h for help.".to_string());
+            }
+            (query, root.unwrap_or_else(|| ".".to_string()))
+        };
+        let resume_from = resume_from.map(|resume| resolve_resume(&resume, Path::new(&root))).transpose()?;
+        // Asking for the field turns the counting on
+        if json_fields.as_ref().is_some_and(|fields: &Vec<JsonField>| fields.contains(&JsonField::Lines)) {
+            line_counts = true;
+        }
+        if compare.is_none() && json_fields.as_ref().is_some_and(|fields: &Vec<JsonField>| fields.contains(&JsonField::Compare)) {
+            return Err("The compare field for --json-fields needs --compare".to_string());
         }
-        let query = query.ok_or("Missing query argument. Use -h for help.")?;
-        let root = root.unwrap_or_else(|| ".".to_string());
+        // --show-rank, --mark-hidden, --line-counts, --diff-against and --compare add their fields to the default JSON fields
+        let json_fields = json_fields.unwrap_or_else(|| {
+            let mut fields = vec![JsonField::Path, JsonField::Score, JsonField::Type];
+            if show_rank {
+                fields.insert(0, JsonField::Rank);
+            }
+            if mark_hidden {
+                fields.push(JsonField::Hidden);
+            }
+            if line_counts {
+                fields.push(JsonField::Lines);
+            }
+            if diff_against.is_some() {
+                fields.push(JsonField::Change);
+            }
+            if compare.is_some() {
+                fields.push(JsonField::Compare);
+            }
+            fields
+        });
+        // Match the literal query: no normalization, no naming-convention
+        // rewrite and no case folding unless -i asks for it
+        if verbatim {
+            normalize = Normalization::None;
+            transform = QueryTransform::None;
+        }
+        // An explicit -s/-i always wins over --fs-case
+        let case = match case {
+            Some(case) => case,
+            None if verbatim => CaseSensitivity::Sensitive,
+            None if fs_case && !(help || version) => detect_fs_case(Path::new(&root)),
+            None => CaseSensitivity::Insensitive,
+        };
         Ok(Config {
             query,
             root,
             num,
+            display_min_score,
+            sample,
+            seed,
             files_only,
             dirs_only,
+            has_ext,
             case,
+            normalize,
+            transform,
+            scoring: ScoringConfig { ranking, near_exact_boost, cap },
+            tiebreak,
+            path_boosts,
             parallel,
+            parallel_threshold,
+            parallel_chunk_size,
+            strict_walk,
+            sorted_walk,
+            resume_from,
+            walk_max_entries,
+            limit_memory,
+            include_git,
+            include_special,
+            owner,
+            group,
+            follow_symlinks,
+            match_target_name,
+            same_filesystem,
+            dedupe_hardlinks,
+            prune,
+            allow_mounts,
+            format,
+            theme,
+            score_transform,
+            json_fields,
+            mark_hidden,
+            line_counts,
+            dir_slash,
+            collapse_prefix,
+            quote,
+            show_rank,
+            diff_against,
+            preview,
+            pipe,
+            line_buffered,
+            simple_color,
+            show_counts,
+            limit_warning,
+            list_filters,
+            nth,
+            open,
+            fail_if_ambiguous,
+            ambiguity_margin,
+            score_debug,
+            compare,
+            calibrate,
+            why_above,
+            match_report,
+            progress_json,
+            stats_json,
+            min_entries,
+            max_entries,
+            max_gap,
+            min_components,
+            max_components,
+            suffix,
+            not_exact,
+            above_median,
+            exact_first,
+            per_tier,
+            scope_dir,
             help,
             version,
         })
     }
 }
 
+// Spell the --resume-from point the way the walk spells paths: the root
+// joined with the rest. A relative point is taken relative to the root, with
+// or without the root's own prefix, and a leading `./` on either is ignored.
+fn resolve_resume(resume: &Path, root: &Path) -> Result<PathBuf, String> {
+    let bare = |p: &Path| p.strip_prefix(".").unwrap_or(p).to_path_buf();
+    let rest = match bare(resume).strip_prefix(bare(root)) {
+        Ok(rest) => rest.to_path_buf(),
+        Err(_) if resume.is_relative() => bare(resume),
+        // An absolute point under a relative root
+        Err(_) => fs::canonicalize(root).ok()
+            .and_then(|abs| resume.strip_prefix(abs).ok().map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from("..")),
+    };
+    if rest.components().any(|c| c == std::path::Component::ParentDir) {
+        return Err(format!("--resume-from {} is not under the root {}", resume.display(), root.display()));
+    }
+    Ok(root.join(rest))
+}
+
+// Trim the ends and squeeze each run of whitespace into one space
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A path found during the walk, with what the walk learned about it
+struct Candidate {
+    path: PathBuf,
+    // Number of immediate children, for directories the walk could read
+    entries: Option<usize>,
+}
+
+// Where the walk puts candidates. It collects all of them unless
+// --limit-memory is hit, then scores them as they come and keeps only the
+// best `keep` matches.
+enum Sink {
+    Collect { candidates: Vec<Candidate>, bytes: usize },
+    Stream { top: BinaryHeap<Reverse<ScoredPath>>, keep: usize, matched: usize },
+}
+
+struct Walk {
+    // Entries found, including one past --walk-max-entries if the walk was cut
+    seen: usize,
+    sink: Sink,
+    last_progress: Instant,
+    // Device and inode of multiply linked files, for --dedupe-hardlinks
+    inodes: HashSet<(u64, u64)>,
+}
+
+impl Walk {
+    fn new() -> Self {
+        Walk { seen: 0, sink: Sink::Collect { candidates: Vec::new(), bytes: 0 }, last_progress: Instant::now(), inodes: HashSet::new() }
+    }
+
+    fn push(&mut self, candidate: Candidate, config: &Config) {
+        self.seen += 1;
+        if self.seen > config.walk_max_entries {
+            return;
+        }
+        if config.progress_json && self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+            // Matches are only known this early once --limit-memory streams
+            let matched = match &self.sink {
+                Sink::Stream { matched, .. } => *matched,
+                Sink::Collect { .. } => 0,
+            };
+            print_progress(self.seen, matched, false);
+            self.last_progress = Instant::now();
+        }
+        match &mut self.sink {
+            Sink::Collect { candidates, bytes } => {
+                // Coarse estimate: the struct plus the path's bytes
+                *bytes += std::mem::size_of::<Candidate>() + candidate.path.as_os_str().len();
+                candidates.push(candidate);
+                if config.limit_memory.is_some_and(|cap| *bytes > cap) && can_stream(config) {
+                    let collected = std::mem::take(candidates);
+                    let keep = config.num.max(config.nth.unwrap_or(0)).max(2);
+                    self.sink = Sink::Stream { top: BinaryHeap::new(), keep, matched: 0 };
+                    for c in collected {
+                        self.stream(c, config);
+                    }
+                }
+            }
+            Sink::Stream { .. } => self.stream(candidate, config),
+        }
+    }
+
+    fn stream(&mut self, candidate: Candidate, config: &Config) {
+        let Sink::Stream { top, keep, matched } = &mut self.sink else {
+            return;
+        };
+        if let Some(sp) = filter_and_score(&candidate, &config.query, config) {
+            *matched += 1;
+            top.push(Reverse(sp));
+            if top.len() > *keep {
+                top.pop();
+            }
+        }
+    }
+}
+
+// Options that look at every candidate or match can't work from a bounded
+// heap, so --limit-memory leaves the walk collecting when one is set
+fn can_stream(config: &Config) -> bool {
+    config.scope_dir.is_none() && !config.above_median && config.sample.is_none() && config.compare.is_none()
+        && !config.exact_first && config.per_tier.is_none()
+        && !config.calibrate && !config.match_report
+}
+
+// Probe whether the filesystem under `root` treats names case-sensitively.
+// Takes an entry with letters in its name, flips their case and checks
+// whether the flipped name still resolves without being a separate entry.
+// With nothing to probe, falls back to the platform's usual default.
+fn detect_fs_case(root: &Path) -> CaseSensitivity {
+    let probe = fs::read_dir(root).ok().and_then(|entries| {
+        let names: HashSet<String> = entries
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .collect();
+        resolves_flipped(&names, |flipped| fs::symlink_metadata(root.join(flipped)).is_ok())
+    });
+    match probe {
+        Some(true) => CaseSensitivity::Insensitive,
+        Some(false) => CaseSensitivity::Sensitive,
+        None if cfg!(any(target_os = "macos", target_os = "windows")) => CaseSensitivity::Insensitive,
+        None => CaseSensitivity::Sensitive,
+    }
+}
+
+// The probe itself: whether `exists` finds the case-flipped form of the
+// first name that has one and isn't itself among `names`
+fn resolves_flipped(names: &HashSet<String>, exists: impl Fn(&str) -> bool) -> Option<bool> {
+    names.iter().find_map(|name| {
+        let flipped: String = name.chars()
+            .map(|c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+            .collect();
+        if flipped == *name || names.contains(&flipped) {
+            return None;
+        }
+        Some(exists(&flipped))
+    })
+}
+
 // Struct to hold a candidate path and its score
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 struct ScoredPath {
     score: i32,
     path: PathBuf,
+    // Only looked up for --tiebreak mtime
+    mtime: Option<SystemTime>,
 }
 
 // For max-heap (highest score first)
 impl Ord for ScoredPath {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.score.cmp(&other.score).then_with(|| other.path.cmp(&self.path))
+        // Equal scores: newer mtime first (when known), then lexical path order
+        self.score.cmp(&other.score)
+            .then_with(|| self.mtime.cmp(&other.mtime))
+            .then_with(|| other.path.cmp(&self.path))
     }
 }
 impl PartialOrd for ScoredPath {
@@ -123,16 +917,397 @@ fn print_help() {
     println!("  -h, --help            Show this help message");
     println!("  -v, --version         Show version info");
     println!("  -n, --num <N>         Limit number of results (default: 10)");
+    println!("      --display-min-score <N>  List only results scoring at least N; counts still include all matches");
+    println!("      --sample <N>      Show N matches picked at random, weighted by score, instead of the top N");
+    println!("      --seed <N>        Seed for --sample, for reproducible picks");
     println!("      --files-only      Only show files");
     println!("      --dirs-only       Only show directories");
+    println!("      --no-ext          Only files without an extension, e.g. Makefile (dirs unaffected)");
+    println!("      --require-ext     Only files with an extension (dirs unaffected)");
     println!("  -i, --ignore-case     Case-insensitive search (default)");
     println!("  -s, --case-sensitive  Case-sensitive search");
+    println!("      --fs-case         Match case the way the root's filesystem does");
+    println!("      --verbatim        Match the literal query: no whitespace tidying, normalization, transform or case folding (-i still applies)");
+    println!("      --normalize <F>   Unicode form for matching: nfc (default), nfd, none");
+    println!("      --query-transform <T>  Rewrite query and names to none, snake, camel or kebab case");
+    println!("      --rank <R>        Base score: alignment (default) or density of the matched chars");
+    println!("      --near-exact-boost <N>  Boost for names exact up to case and extension, {}..{} (default: {})",
+        PREFIX_BOOST, EXACT_BOOST - 1, DEFAULT_NEAR_EXACT_BOOST);
+    println!("      --cap-score <N>   Clamp scores to at most N, so the tiebreak can reorder top matches");
+    println!("      --boost-path <S>:<N>  Add N (negative to demote) to matches whose path contains S (repeatable)");
+    println!("      --tiebreak <T>    Order equal scores by path (default) or newest mtime first");
     println!("      --no-parallel     Disable parallel scoring");
+    println!("      --parallel-threshold <N>  Score in parallel only above N candidates (default: {})", DEFAULT_PARALLEL_THRESHOLD);
+    println!("      --parallel-chunk-size <N>  Score at least N candidates per parallel task (default: auto)");
+    println!("      --strict-walk     Abort on the first traversal error");
+    println!("      --sorted-walk     Visit each directory's entries in lexical order");
+    println!("      --resume-from <P> Only walk paths after P in sorted order (implies --sorted-walk)");
+    println!("      --walk-max-entries <N>  Stop the walk after N entries (default: {})", DEFAULT_WALK_MAX_ENTRIES);
+    println!("      --limit-memory <MB>  Keep only the best matches once candidates would outgrow MB");
+    println!("      --include-git     Also search .git directories (skipped by default)");
+    println!("      --include-special Also list FIFOs, sockets and device nodes (Unix)");
+    println!("      --owner <U>       Only entries owned by user U, a name or uid (Unix)");
+    println!("      --group <G>       Only entries owned by group G, a name or gid (Unix)");
+    println!("  -L, --follow-symlinks Descend into symlinked directories (they are always listed)");
+    println!("      --match-target-name  Also match symlinks by their target's name, shown as link -> target");
+    println!("  -x, --same-filesystem Don't descend into other filesystems (Unix only)");
+    println!("      --allow-mount <P> Descend into mount point P even with -x (repeatable)");
+    println!("      --prune           List exact and prefix directory matches without descending into them");
+    println!("      --dedupe-hardlinks  List a hardlinked file under its first name found only (Unix only)");
+    println!("      --format <F>      Output format: human (default), path, editor, nul, json, jsonl, shell");
+    println!("      --editor-format   Print plain path:line:col jump targets (--format editor)");
+    println!("  -0, --print0          Print raw paths separated by NUL bytes (--format nul)");
+    println!("      --score-transform <T>  Show scores as-is (none), on a log scale (log) or as ranks (rank)");
+    println!("      --json-fields <F> Fields and order for json/jsonl, from rank,path,score,type,hidden,change,lines,compare");
+    println!("      --show-rank       Number the results from 1 in human and JSON output");
+    println!("      --line-counts     Show each file result's line count (? if unreadable or binary)");
+    println!("      --collapse-prefix Print the results' common directory once, then paths relative to it");
+    println!("      --quote           Single-quote the paths in human output, for pasting into a shell");
+    println!("      --no-slash        Don't append / to directories in human and path output");
+    println!("      --mark-hidden     Tag results inside dotfiles or dot directories as [hidden]");
+    println!("      --diff-against <F>  Mark results as new or moved, and list removed ones, versus a saved json/jsonl run");
+    println!("      --theme <T>       Result colors: default, matched-only (highlight matched chars only)");
+    println!("      --preview <CMD>   Show the first lines of CMD's output under each result, {{}} is the path");
+    println!("      --pipe <CMD>      Write the result paths to CMD's stdin, one per line (NUL-separated with -0)");
+    println!("      --simple-color    Use only basic 8-color codes, no bold or dim (default with TERM=dumb)");
+    println!("      --line-buffered   Flush after every result line (default: block-buffered)");
+    println!("      --show-counts     Print how many matches were shown out of the total");
+    println!("      --no-limit-warning  Don't hint on stderr when most matches are cut off by --num");
+    println!("      --list-filters    Print the effective filters and exit");
+    println!("      --nth <N>         Print only the path of the Nth-ranked result");
+    println!("      --open            Open the top result (or the --nth one) in $VISUAL or $EDITOR");
+    println!("      --fail-if-ambiguous  Exit with an error if the top two scores are within the margin");
+    println!("      --ambiguity-margin <N>  Score gap that still counts as ambiguous (default: {})", DEFAULT_AMBIGUITY_MARGIN);
+    println!("      --score-debug     Show each scoring component per result (unstable)");
+    println!("      --compare <Q2>    Also show each result's score and rank for query Q2");
+    println!("      --calibrate       Print the score distribution and a suggested --display-min-score, then exit");
+    println!("      --why-above <A> <B>  Compare the scoring components of paths A and B, then exit");
+    println!("      --match-report    Summarize how well the query fits all matches");
+    println!("      --progress-json   Print {{\"scanned\",\"matched\"}} progress lines on stderr while searching");
+    println!("      --stats-json      Print search timings and counters as JSON on stderr");
+    println!("      --min-entries <N> Only directories with at least N children (implies --dirs-only)");
+    println!("      --max-entries <N> Only directories with at most N children (implies --dirs-only)");
+    println!("      --max-gap <N>     Require the whole query in order, at most N chars apart");
+    println!("      --min-components <N>  Only paths with at least N components, root included");
+    println!("      --max-components <N>  Only paths with at most N components, root included");
+    println!("      --suffix          Only names ending with the query (alias: --anchored-end)");
+    println!("      --not-exact       Drop names equal to the query, keeping every other match");
+    println!("      --above-median    Drop matches scoring below the median match score");
+    println!("      --per-tier <N>    Show at most N results with the same score");
+    println!("      --exact-first     Show exact and prefix matches first, filling up with fuzzy ones");
+    println!("      --scope-dir <Q>   Only search under directories whose name contains Q's chars in order");
     println!("\n\x1b[1mEXAMPLES\x1b[0m:");
     println!("  shodh kilo src --files-only -n 20");
     println!("  shodh resume ~/Documents --dirs-only");
 }
 
+// One line of --list-filters; repeatable options list every value given
+enum FilterValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn filter_entries(config: &Config) -> Vec<(&'static str, FilterValue)> {
+    use FilterValue::{Many, One};
+    let kind = match (config.files_only, config.dirs_only) {
+        (true, true) => "none (--files-only and --dirs-only)",
+        (true, false) => "files",
+        (false, true) => "directories",
+        (false, false) => "files and directories",
+    };
+    let extension = match config.has_ext {
+        Some(true) => "required",
+        Some(false) => "none",
+        None => "any",
+    };
+    let fmt_score = |score: Option<i32>| score.map_or_else(|| "*".to_string(), |n| n.to_string());
+    vec![
+        ("query", One(config.query.clone())),
+        ("root", One(config.root.clone())),
+        ("types", One(kind.to_string())),
+        ("extension", One(extension.to_string())),
+        ("scope dir", One(config.scope_dir.clone().unwrap_or_else(|| "*".to_string()))),
+        ("case", One(format!("{:?}", config.case))),
+        ("normalize", One(format!("{:?}", config.normalize))),
+        ("transform", One(format!("{:?}", config.transform))),
+        ("rank", One(format!("{:?}", config.scoring.ranking))),
+        ("near exact", One(config.scoring.near_exact_boost.to_string())),
+        ("score cap", One(fmt_score(config.scoring.cap))),
+        ("path boost", Many(config.path_boosts.iter().map(|(part, boost)| format!("{:+} for {:?}", boost, part)).collect())),
+        ("tiebreak", One(format!("{:?}", config.tiebreak))),
+        ("entries", One(format!("{}..{}", fmt_bound(config.min_entries), fmt_bound(config.max_entries)))),
+        ("max gap", One(fmt_bound(config.max_gap))),
+        ("components", One(format!("{}..{}", fmt_bound(config.min_components), fmt_bound(config.max_components)))),
+        ("suffix", One(config.suffix.to_string())),
+        ("not exact", One(config.not_exact.to_string())),
+        ("median", One(config.above_median.to_string())),
+        ("exact first", One(config.exact_first.to_string())),
+        ("per tier", One(fmt_bound(config.per_tier))),
+        ("num", One(config.num.to_string())),
+        ("display", One(format!("score >= {}", fmt_score(config.display_min_score)))),
+        ("strict", One(config.strict_walk.to_string())),
+        ("walk cap", One(config.walk_max_entries.to_string())),
+        ("memory", One(config.limit_memory.map_or_else(|| "*".to_string(), |b| format!("{} MB", b / (1024 * 1024))))),
+        ("git dirs", One(config.include_git.to_string())),
+        ("special", One(config.include_special.to_string())),
+        ("owner", One(fmt_bound(config.owner.map(|id| id as usize)))),
+        ("group", One(fmt_bound(config.group.map(|id| id as usize)))),
+        ("symlinks", One(if config.follow_symlinks { "follow" } else { "list only" }.to_string())),
+        ("link names", One(if config.match_target_name { "link and target" } else { "link" }.to_string())),
+        ("same fs", One(config.same_filesystem.to_string())),
+        ("prune", One(config.prune.to_string())),
+        ("hardlinks", One(if config.dedupe_hardlinks { "first name only" } else { "every name" }.to_string())),
+        ("allow", Many(config.allow_mounts.iter().map(|mount| mount.display().to_string()).collect())),
+        ("sorted", One(config.sorted_walk.to_string())),
+        ("resume", One(config.resume_from.as_ref().map_or_else(|| "*".to_string(), |p| p.display().to_string()))),
+    ]
+}
+
+// The effective filters as `label: value` lines, or one JSON object for the
+// json formats, keyed by the labels in snake_case. Repeatable options are
+// arrays there, and get one line per value (or none) in the text.
+fn write_filters(out: &mut dyn Write, config: &Config) -> io::Result<()> {
+    let entries = filter_entries(config);
+    if !matches!(config.format, OutputFormat::Json | OutputFormat::Jsonl) {
+        let width = entries.iter().map(|(label, _)| label.len() + 1).max().unwrap_or(0);
+        for (label, value) in &entries {
+            let values = match value {
+                FilterValue::One(v) => std::slice::from_ref(v),
+                FilterValue::Many(vs) => vs.as_slice(),
+            };
+            for v in values {
+                writeln!(out, "{:<width$} {}", format!("{}:", label), v)?;
+            }
+        }
+        return Ok(());
+    }
+    write!(out, "{{")?;
+    for (i, (label, value)) in entries.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "{}:", json_string(&label.replace(' ', "_")))?;
+        match value {
+            FilterValue::One(v) => write!(out, "{}", json_string(v))?,
+            FilterValue::Many(vs) => {
+                let items: Vec<String> = vs.iter().map(|v| json_string(v)).collect();
+                write!(out, "[{}]", items.join(","))?;
+            }
+        }
+    }
+    writeln!(out, "}}")
+}
+
+fn fmt_bound(bound: Option<usize>) -> String {
+    bound.map_or_else(|| "*".to_string(), |n| n.to_string())
+}
+
+// Aggregate match quality over every match, for --match-report
+struct MatchReport {
+    matches: usize,
+    // Mean share of the query's chars the alignment matched
+    coverage: f64,
+    // Mean number of unmatched candidate chars between matched ones
+    gap: f64,
+    exact: usize,
+    near_exact: usize,
+    prefix: usize,
+    fuzzy: usize,
+}
+
+impl MatchReport {
+    fn new(ranked: &[ScoredPath], config: &Config) -> Self {
+        let mut report = MatchReport { matches: 0, coverage: 0.0, gap: 0.0, exact: 0, near_exact: 0, prefix: 0, fuzzy: 0 };
+        for sp in ranked {
+            let Some(name) = scored_name(&sp.path, &config.query, config) else {
+                continue;
+            };
+            let (query, candidate) = prepare(&config.query, &name, config);
+            let b = fuzzy_breakdown(&query, &candidate, config.scoring);
+            if b.exact_boost > 0 {
+                report.exact += 1;
+            } else if b.near_exact_boost > 0 {
+                report.near_exact += 1;
+            } else if b.prefix_boost > 0 {
+                report.prefix += 1;
+            } else {
+                report.fuzzy += 1;
+            }
+            let positions = match_positions(&query, &candidate);
+            report.coverage += positions.len() as f64 / query.chars().count().max(1) as f64;
+            if let (Some(first), Some(last)) = (positions.first(), positions.last()) {
+                let unmatched = last - first + 1 - positions.len();
+                report.gap += unmatched as f64 / (positions.len() - 1).max(1) as f64;
+            }
+            report.matches += 1;
+        }
+        if report.matches > 0 {
+            report.coverage /= report.matches as f64;
+            report.gap /= report.matches as f64;
+        }
+        report
+    }
+
+    fn fraction(&self, n: usize) -> f64 {
+        if self.matches == 0 { 0.0 } else { n as f64 / self.matches as f64 }
+    }
+
+    fn write_human(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "\n\x1b[1mMatch report\x1b[0m ({} matches):", self.matches)?;
+        writeln!(out, "  coverage:   {:.1}% of the query matched on average", self.coverage * 100.0)?;
+        writeln!(out, "  gap:        {:.2} chars between matched chars on average", self.gap)?;
+        writeln!(out, "  exact:      {:.1}%", self.fraction(self.exact) * 100.0)?;
+        writeln!(out, "  near exact: {:.1}%", self.fraction(self.near_exact) * 100.0)?;
+        writeln!(out, "  prefix:     {:.1}%", self.fraction(self.prefix) * 100.0)?;
+        writeln!(out, "  fuzzy:      {:.1}%", self.fraction(self.fuzzy) * 100.0)
+    }
+
+    fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out,
+            "{{\"matches\":{},\"coverage\":{:.4},\"gap\":{:.4},\"exact\":{:.4},\"near_exact\":{:.4},\"prefix\":{:.4},\"fuzzy\":{:.4}}}",
+            self.matches, self.coverage, self.gap,
+            self.fraction(self.exact), self.fraction(self.near_exact), self.fraction(self.prefix), self.fraction(self.fuzzy))
+    }
+}
+
+// One --progress-json line; stderr keeps it apart from the results on stdout
+fn print_progress(scanned: usize, matched: usize, done: bool) {
+    eprintln!("{{\"scanned\":{},\"matched\":{},\"done\":{}}}", scanned, matched, done);
+}
+
+// Timings and counters for --stats-json; the keys are a stable interface
+struct Stats {
+    candidates: usize,
+    matched: usize,
+    shown: usize,
+    walk: Duration,
+    score: Duration,
+    total: Duration,
+}
+
+impl Stats {
+    fn print_json(&self) {
+        eprintln!(
+            "{{\"candidates\":{},\"matched\":{},\"shown\":{},\"walk_ms\":{:.3},\"score_ms\":{:.3},\"total_ms\":{:.3}}}",
+            self.candidates,
+            self.matched,
+            self.shown,
+            self.walk.as_secs_f64() * 1000.0,
+            self.score.as_secs_f64() * 1000.0,
+            self.total.as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+// Print how many matches got each score, best first, and suggest a display
+// floor at the widest gap between neighbouring scores, which usually splits
+// the boosted or close matches from the noise
+fn print_calibration(ranked: &[ScoredPath]) {
+    let tiers = score_tiers(ranked);
+    println!("score distribution ({} matches):", ranked.len());
+    for (score, count) in &tiers {
+        println!("  {:>6}  {}", score, count);
+    }
+    match widest_gap(&tiers) {
+        Some((above, below)) => println!("suggested: --display-min-score {} (gap from {} down to {})", above, above, below),
+        None => println!("suggested: none, fewer than two distinct scores"),
+    }
+}
+
+// Each distinct score of `ranked` (sorted best first) with its match count
+fn score_tiers(ranked: &[ScoredPath]) -> Vec<(i32, usize)> {
+    let mut tiers: Vec<(i32, usize)> = Vec::new();
+    for sp in ranked {
+        match tiers.last_mut() {
+            Some((score, count)) if *score == sp.score => *count += 1,
+            _ => tiers.push((sp.score, 1)),
+        }
+    }
+    tiers
+}
+
+// The neighbouring scores furthest apart, higher one first
+fn widest_gap(tiers: &[(i32, usize)]) -> Option<(i32, i32)> {
+    tiers.windows(2).max_by_key(|w| w[0].0 - w[1].0).map(|w| (w[0].0, w[1].0))
+}
+
+// Named score components of one path for --why-above, summing to its score.
+// The cap shows up as a negative adjustment, before the path boosts.
+type Components = Vec<(&'static str, i32)>;
+
+fn score_components(path: &Path, config: &Config) -> Result<Components, String> {
+    let name = scored_name(path, &config.query, config)
+        .ok_or_else(|| format!("{}: no file name to score", path.display()))?;
+    let (query, candidate) = prepare(&config.query, &name, config);
+    let b = fuzzy_breakdown(&query, &candidate, config.scoring);
+    let mut components = vec![
+        ("alignment", b.alignment),
+        ("exact_boost", b.exact_boost),
+        ("near_exact_boost", b.near_exact_boost),
+        ("prefix_boost", b.prefix_boost),
+    ];
+    if config.scoring.cap.is_some() {
+        components.push(("cap", fuzzy_score(&query, &candidate, config.scoring) - b.total()));
+    }
+    if !config.path_boosts.is_empty() {
+        components.push(("path_boost", path_boost(path, config)));
+    }
+    Ok(components)
+}
+
+// --why-above: write both paths' components side by side, the one that
+// ranks first on the left, and mark the component that puts it ahead most
+fn write_pair_explanation(out: &mut dyn Write, a: &Path, b: &Path, config: &Config) -> Result<(), String> {
+    let scored = |path: &Path| -> Result<(ScoredPath, Components, bool), String> {
+        let components = score_components(path, config)?;
+        let score = components.iter().fold(0, |sum: i32, (_, n)| sum.saturating_add(*n));
+        let matched = scored_name(path, &config.query, config).is_some_and(|name| score_name(&config.query, &name, config).is_some());
+        let mtime = match config.tiebreak {
+            TieBreak::Mtime => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            TieBreak::Path => None,
+        };
+        Ok((ScoredPath { score, path: path.to_path_buf(), mtime }, components, matched))
+    };
+    let (mut first, mut second) = (scored(a)?, scored(b)?);
+    if second.0 > first.0 {
+        std::mem::swap(&mut first, &mut second);
+    }
+    let (first_path, second_path) = (first.0.path.display().to_string(), second.0.path.display().to_string());
+    // With equal scores no component decides; the tiebreak does
+    let decisive = first.1.iter().zip(&second.1)
+        .filter(|_| first.0.score != second.0.score)
+        .map(|((name, x), (_, y))| (*name, x - y))
+        .filter(|&(_, diff)| diff > 0)
+        .max_by_key(|&(_, diff)| diff)
+        .map(|(name, _)| name);
+    let written = (|| -> io::Result<()> {
+        writeln!(out, "{} ranks above {} for {:?}:", first_path, second_path, config.query)?;
+        writeln!(out, "  {:<16}  {:>8}  {:>8}  {:>7}", "component", "first", "second", "diff")?;
+        for ((name, x), (_, y)) in first.1.iter().zip(&second.1) {
+            let mark = if decisive == Some(*name) { format!("  {}", paint(BOLD, "<- decisive", config.theme)) } else { String::new() };
+            writeln!(out, "  {:<16}  {:>8}  {:>8}  {:>+7}{}", name, x, y, x - y, mark)?;
+        }
+        writeln!(out, "  {:<16}  {:>8}  {:>8}  {:>+7}", "score", first.0.score, second.0.score, first.0.score - second.0.score)?;
+        if first.0.score == second.0.score {
+            match (config.tiebreak, first.0.mtime != second.0.mtime) {
+                (TieBreak::Mtime, true) => writeln!(out, "equal scores: the newer mtime ranks first")?,
+                _ => writeln!(out, "equal scores: path order decides")?,
+            }
+        }
+        for (sp, _, matched) in [&first, &second] {
+            if !*matched {
+                writeln!(out, "{}", paint(DIM, &format!("note: {} is not a match and would not be listed", sp.path.display()), config.theme))?;
+            }
+        }
+        out.flush()
+    })();
+    match written {
+        // A closed pipe just means the reader has seen enough
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(format!("writing the explanation: {}", e)),
+        _ => Ok(()),
+    }
+}
+
 fn print_version() {
     println!("shodh v{}", VERSION);
 }
@@ -153,120 +1328,2768 @@ fn main() {
         print_version();
         return;
     }
-    let mut candidates = Vec::new();
-    if let Err(e) = walk_dir(Path::new(&config.root), &mut candidates) {
-        eprintln!("\x1b[1;31mError traversing directory:\x1b[0m {}", e);
-        std::process::exit(1);
+    if cfg!(not(unix)) && (config.owner.is_some() || config.group.is_some()) {
+        eprintln!("\x1b[1;33mWarning:\x1b[0m --owner and --group only work on Unix and are ignored");
     }
-    let scored: Vec<_> = if config.parallel {
-        candidates.par_iter()
-            .filter_map(|path| filter_and_score(path, &config))
-            .collect()
-    } else {
-        candidates.iter()
-            .filter_map(|path| filter_and_score(path, &config))
-            .collect()
-    };
-    let mut heap = BinaryHeap::new();
-    for sp in scored {
-        heap.push(sp);
+    if config.list_filters {
+        if let Err(e) = write_filters(&mut io::stdout().lock(), &config)
+            && e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("\x1b[1;31mError writing filters:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    println!("\x1b[1;32m\nResults:\x1b[0m");
-    let mut shown = 0;
-    for sp in heap.into_sorted_vec().into_iter().rev().take(config.num) {
-        let (ty, color) = if sp.path.is_dir() {
-            ("DIR ", "\x1b[1;34m")
-        } else {
-            ("FILE", "\x1b[1;33m")
+    // Scores the two names directly, without walking
+    if let Some((a, b)) = &config.why_above {
+        let mut buffered = buffer_output(io::stdout().lock(), &config);
+        if let Err(e) = write_pair_explanation(&mut *styled_output(&mut *buffered, &config), a, b, &config) {
+            eprintln!("\x1b[1;31mError:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let start = Instant::now();
+    let Search { scanned, candidates: candidate_count, ranked, total, comparison, walked } = match search(&config) {
+        Ok(search) => search,
+        Err(e) => {
+            eprintln!("\x1b[1;31mError traversing directory:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
+    };
+    if config.progress_json {
+        print_progress(scanned, total, true);
+    }
+    let results = select_results(&ranked, &config);
+    if config.stats_json {
+        let now = Instant::now();
+        Stats {
+            candidates: candidate_count,
+            matched: total,
+            shown: match config.nth {
+                Some(n) => usize::from(n <= results.len()),
+                None if config.open => usize::from(!results.is_empty()),
+                None => results.len(),
+            },
+            walk: walked - start,
+            score: now - walked,
+            total: now - start,
+        }.print_json();
+    }
+    if config.calibrate {
+        print_calibration(&ranked);
+        return;
+    }
+    if config.fail_if_ambiguous && let [first, second, ..] = ranked.as_slice() && first.score - second.score <= config.ambiguity_margin {
+        eprintln!("\x1b[1;31mError:\x1b[0m ambiguous query: {} ({}) and {} ({}) score within {}",
+            first.path.display(), first.score, second.path.display(), second.score, config.ambiguity_margin);
+        std::process::exit(1);
+    }
+    if config.open {
+        let n = config.nth.unwrap_or(1);
+        let Some(sp) = results.get(n - 1) else {
+            eprintln!("\x1b[1;31mError:\x1b[0m nothing to open: result #{} requested but only {} results found", n, results.len());
+            std::process::exit(1);
         };
-        println!("{}[{:5}] {}{}\x1b[0m  {}", color, sp.score, ty, color, sp.path.display());
-        shown += 1;
+        if let Err(e) = open_in_editor(&sp.path) {
+            eprintln!("\x1b[1;31mError:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(n) = config.nth {
+        match results.get(n - 1) {
+            Some(sp) => println!("{}", sp.path.display()),
+            None => {
+                eprintln!("\x1b[1;31mError:\x1b[0m --nth {} requested but only {} results found", n, results.len());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let report = config.match_report.then(|| MatchReport::new(&ranked, &config));
+    // --show-counts already reports the totals on stdout, and a sample isn't a top N
+    if config.limit_warning && !config.show_counts && config.sample.is_none() && total > results.len().saturating_mul(LIMIT_WARNING_RATIO) {
+        eprintln!("\x1b[2m{} matches; showing top {} — raise --num or refine your query\x1b[0m", total, results.len());
+    }
+    if let Some(cmd) = &config.pipe {
+        if let Err(e) = pipe_results(cmd, &results, &config) {
+            eprintln!("\x1b[1;31mError:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    if shown == 0 {
-        println!("\x1b[1;31mNo results found.\x1b[0m");
+    let mut buffered = buffer_output(io::stdout().lock(), &config);
+    let out = &mut *styled_output(&mut *buffered, &config);
+    let written = write_results(out, &results, total, comparison.as_ref(), &config);
+    // Structured formats keep stdout parseable, so their report goes to stderr
+    let written = written.and_then(|()| match (&report, config.format) {
+        (Some(report), OutputFormat::Human) => report.write_human(out).and_then(|()| out.flush()),
+        (Some(report), _) => report.write_json(&mut io::stderr()),
+        (None, _) => Ok(()),
+    });
+    if let Err(e) = written {
+        // A closed pipe (e.g. `| head`) just means the reader has seen enough
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("\x1b[1;31mError writing results:\x1b[0m {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-// Recursively walk the directory and collect all file and directory paths
-fn walk_dir(path: &Path, out: &mut Vec<PathBuf>) -> Result<(), String> {
-    let meta = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
-    if meta.is_dir() {
-        let entries = fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
-        for entry in entries {
-            let entry = entry.map_err(|e| format!("{}: {}", path.display(), e))?;
-            let p = entry.path();
-            out.push(p.clone());
-            if p.is_dir() {
-                let _ = walk_dir(&p, out); // Continue on error
+// The results to show out of all matches (best first): the display floor,
+// --per-tier, then --sample, --exact-first or plain --num. --stats-json
+// counts these, so every selection step belongs here. --nth and --open pick
+// one of them by rank, so --num doesn't cut the list for them.
+fn select_results(ranked: &[ScoredPath], config: &Config) -> Vec<ScoredPath> {
+    let num = if config.nth.is_some() || config.open { usize::MAX } else { config.num };
+    // The display floor only hides results; it is applied before --num
+    let eligible = ranked.iter().filter(|sp| config.display_min_score.is_none_or(|min| sp.score >= min));
+    // Equal scores sit next to each other in the ranking, so count each run
+    let mut tier = (None, 0);
+    let eligible = eligible
+        .filter(|sp| {
+            if tier.0 != Some(sp.score) {
+                tier = (Some(sp.score), 0);
             }
+            tier.1 += 1;
+            config.per_tier.is_none_or(|n| tier.1 <= n)
+        })
+        .cloned();
+    match config.sample {
+        Some(n) => {
+            let seed = config.seed.unwrap_or_else(|| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+            });
+            weighted_sample(eligible, n, seed)
         }
-    } else {
-        out.push(path.to_path_buf());
+        // Fuzzy matches only fill the slots the anchored tier leaves over
+        None if config.exact_first => {
+            let (anchored, fuzzy): (Vec<_>, Vec<_>) = eligible.partition(|sp| is_anchored(&sp.path, config));
+            anchored.into_iter().chain(fuzzy).take(num).collect()
+        }
+        None => eligible.take(num).collect(),
     }
-    Ok(())
 }
 
-fn filter_and_score(path: &PathBuf, config: &Config) -> Option<ScoredPath> {
-    let name = path.file_name()?.to_str()?;
-    // Type filtering
-    if config.files_only && !path.is_file() {
-        return None;
+// What a search found. `ranked` holds every match best first, or only the
+// kept ones once --limit-memory streams; `total` always counts them all.
+struct Search {
+    // Entries the walk found, as --progress-json counts them
+    scanned: usize,
+    // Of those, the ones scored: fewer under --scope-dir
+    candidates: usize,
+    ranked: Vec<ScoredPath>,
+    total: usize,
+    comparison: Option<Comparison>,
+    // When the walk finished and scoring started
+    walked: Instant,
+}
+
+// Walk the root and rank what it finds
+fn search(config: &Config) -> Result<Search, String> {
+    let mut walk = Walk::new();
+    walk_dir(Path::new(&config.root), config, &mut walk)?;
+    // The walk overshoots the cap by one entry to tell a full tree from a cut one
+    if walk.seen > config.walk_max_entries {
+        eprintln!("\x1b[1;33mWarning:\x1b[0m stopped walking after {} entries; results are truncated. \
+            Narrow the root or raise --walk-max-entries", config.walk_max_entries);
     }
-    if config.dirs_only && !path.is_dir() {
-        return None;
+    let scanned = walk.seen.min(config.walk_max_entries);
+    Ok(match walk.sink {
+        Sink::Collect { mut candidates, .. } => {
+            if let Some(scope) = &config.scope_dir {
+                candidates = restrict_to_scope(candidates, scope, config);
+            }
+            let walked = Instant::now();
+            let mut ranked = rank(&candidates, &config.query, config);
+            if config.above_median {
+                trim_below_median(&mut ranked);
+            }
+            let total = ranked.len();
+            let comparison = config.compare.as_ref().map(|other| Comparison {
+                primary: ranked.iter().enumerate().map(|(i, sp)| (sp.path.clone(), i + 1)).collect(),
+                other: rank(&candidates, other, config).into_iter()
+                    .enumerate()
+                    .map(|(i, sp)| (sp.path, (i + 1, sp.score)))
+                    .collect(),
+            });
+            Search { scanned, candidates: candidates.len(), ranked, total, comparison, walked }
+        }
+        // Scoring already happened during the walk
+        Sink::Stream { top, matched, .. } => Search {
+            scanned,
+            candidates: scanned,
+            ranked: top.into_sorted_vec().into_iter().map(|Reverse(sp)| sp).collect(),
+            total: matched,
+            comparison: None,
+            walked: Instant::now(),
+        },
+    })
+}
+
+// 1-based ranks of every match under the main query and under --compare's
+// query, with the latter's scores
+struct Comparison {
+    primary: HashMap<PathBuf, usize>,
+    other: HashMap<PathBuf, (usize, i32)>,
+}
+
+// Score every candidate against `query` and sort the matches best first
+fn rank(candidates: &[Candidate], query: &str, config: &Config) -> Vec<ScoredPath> {
+    let scored: Vec<_> = if config.parallel && candidates.len() >= config.parallel_threshold {
+        // By default aim for a few tasks per thread: enough to balance uneven
+        // chunks without splitting cheap per-name scoring finer than needed
+        let chunk = config.parallel_chunk_size
+            .unwrap_or_else(|| (candidates.len() / (rayon::current_num_threads() * PARALLEL_TASKS_PER_THREAD)).max(1));
+        candidates.par_iter()
+            .with_min_len(chunk)
+            .filter_map(|c| filter_and_score(c, query, config))
+            .collect()
+    } else {
+        candidates.iter()
+            .filter_map(|c| filter_and_score(c, query, config))
+            .collect()
+    };
+    // Narrow searches often leave a single match, which needs no ordering
+    if scored.len() <= 1 {
+        return scored;
     }
-    // Case sensitivity
-    let (query, candidate) = match config.case {
-        CaseSensitivity::Insensitive => (config.query.to_lowercase(), name.to_lowercase()),
-        CaseSensitivity::Sensitive => (config.query.clone(), name.to_string()),
+    let mut heap = BinaryHeap::new();
+    for sp in scored {
+        heap.push(sp);
+    }
+    let mut ranked: Vec<ScoredPath> = heap.into_sorted_vec();
+    ranked.reverse();
+    ranked
+}
+
+fn write_results(
+    out: &mut dyn Write,
+    results: &[ScoredPath],
+    total: usize,
+    comparison: Option<&Comparison>,
+    config: &Config,
+) -> io::Result<()> {
+    // Counted up front so the files are read in parallel
+    let lines: Vec<Option<usize>> = if config.line_counts && matches!(config.format, OutputFormat::Human | OutputFormat::Json | OutputFormat::Jsonl) {
+        results.par_iter().map(|sp| count_lines(&sp.path)).collect()
+    } else {
+        Vec::new()
     };
-    let score = fuzzy_score(&query, &candidate);
-    if score > 0 {
-        Some(ScoredPath { score, path: path.clone() })
+    match config.format {
+        OutputFormat::Human => {}
+        OutputFormat::Path => {
+            for sp in results {
+                write_raw_path(out, &sp.path)?;
+                writeln!(out, "{}", dir_suffix(&sp.path, config))?;
+            }
+            return out.flush();
+        }
+        OutputFormat::Nul => {
+            for sp in results {
+                write_raw_path(out, &sp.path)?;
+                out.write_all(b"\0")?;
+            }
+            return out.flush();
+        }
+        // Always valid: `[]` or no lines at all when nothing matched.
+        // --show-counts wraps the array as {"total":..,"shown":..,"results":[..]}
+        OutputFormat::Json => {
+            if config.show_counts {
+                write!(out, "{{\"total\":{},\"shown\":{},\"results\":", total, results.len())?;
+            }
+            write!(out, "[")?;
+            for (i, sp) in results.iter().enumerate() {
+                if i > 0 {
+                    write!(out, ",")?;
+                }
+                write_json_result(out, sp, i, &lines, comparison, config)?;
+            }
+            for (i, path) in removed_paths(results, config).into_iter().enumerate() {
+                if i > 0 || !results.is_empty() {
+                    write!(out, ",")?;
+                }
+                write!(out, "{{\"path\":{},\"change\":\"removed\"}}", json_string(path))?;
+            }
+            write!(out, "]")?;
+            if config.show_counts {
+                write!(out, "}}")?;
+            }
+            writeln!(out)?;
+            return out.flush();
+        }
+        OutputFormat::Jsonl => {
+            for (i, sp) in results.iter().enumerate() {
+                write_json_result(out, sp, i, &lines, comparison, config)?;
+                writeln!(out)?;
+            }
+            for path in removed_paths(results, config) {
+                writeln!(out, "{{\"path\":{},\"change\":\"removed\"}}", json_string(path))?;
+            }
+            // A last line without a path, so readers of the results skip it
+            if config.show_counts {
+                writeln!(out, "{{\"total\":{},\"shown\":{}}}", total, results.len())?;
+            }
+            return out.flush();
+        }
+        // A parenthesized list for `eval "files=$(shodh --format shell ...)"`
+        OutputFormat::Shell => {
+            let quoted: Vec<String> = results.iter().map(|sp| shell_quote(&sp.path.display().to_string())).collect();
+            writeln!(out, "({})", quoted.join(" "))?;
+            return out.flush();
+        }
+        OutputFormat::Editor => {
+            // Name matches have no position, so jump to the top of the file
+            for sp in results {
+                writeln!(out, "{}:1:1", sp.path.display())?;
+            }
+            return out.flush();
+        }
+    }
+    match config.theme {
+        Theme::Default => writeln!(out, "\x1b[1;32m\nResults:\x1b[0m")?,
+        Theme::MatchedOnly => writeln!(out, "\nResults:")?,
+    }
+    let prefix = if config.collapse_prefix { common_dir(results) } else { None };
+    if let Some(prefix) = &prefix {
+        writeln!(out, "{}", paint(DIM, &format!("{}{}", prefix.display(), std::path::MAIN_SEPARATOR_STR), config.theme))?;
+    }
+    let scores: Vec<String> = results.iter().enumerate()
+        .map(|(i, sp)| display_score(sp.score, i, config.score_transform))
+        .collect();
+    // Widen the score column for the largest score (or sign) so paths line up
+    let width = scores.iter().map(|s| s.len()).max().unwrap_or(0).max(5);
+    let rank_width = results.len().to_string().len();
+    let first_fuzzy = if config.exact_first && config.sample.is_none() {
+        results.iter().position(|sp| !is_anchored(&sp.path, config))
     } else {
         None
+    };
+    for (index, (sp, score)) in results.iter().zip(&scores).enumerate() {
+        let (ty, color) = if sp.path.is_dir() {
+            ("DIR ", "\x1b[1;34m")
+        } else {
+            ("FILE", "\x1b[1;33m")
+        };
+        let mut tags = Vec::new();
+        if config.mark_hidden && is_hidden(&sp.path, config) {
+            tags.push("[hidden]".to_string());
+        }
+        if config.line_counts && sp.path.is_file() {
+            match lines[index] {
+                Some(n) => tags.push(format!("[{} lines]", n)),
+                None => tags.push("[? lines]".to_string()),
+            }
+        }
+        match change_since(&sp.path, index, config) {
+            _ if config.diff_against.is_none() => {}
+            Some(Change::Added) => tags.push("[new]".to_string()),
+            Some(Change::Moved(from)) => tags.push(format!("[was #{}]", from)),
+            Some(Change::Same) | None => {}
+        }
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!("  {}", paint(DIM, &tags.join(" "), config.theme))
+        };
+        let shown = prefix.as_ref().and_then(|p| sp.path.strip_prefix(p).ok()).unwrap_or(&sp.path);
+        if first_fuzzy == Some(index) {
+            writeln!(out, "{}", paint(DIM, "  fuzzy matches:", config.theme))?;
+        }
+        if config.show_rank {
+            write!(out, "{:>rank_width$}. ", index + 1)?;
+        }
+        // A link scored by its target's name has the target highlighted
+        let name = scored_name(&sp.path, &config.query, config).unwrap_or_default();
+        let highlighted = |path: &Path| match config.theme {
+            Theme::Default => path.display().to_string(),
+            Theme::MatchedOnly => highlight_matches(path, &name, config),
+        };
+        // Quoted paths stay plain, since escapes inside the quotes would be
+        // pasted along with the path
+        let mut shown = if config.quote {
+            shell_quote(&format!("{}{}", shown.display(), dir_suffix(&sp.path, config)))
+        } else {
+            format!("{}{}", highlighted(shown), dir_suffix(&sp.path, config))
+        };
+        if config.match_target_name && let Some(target) = link_target(&sp.path) {
+            shown = format!("{} -> {}", shown, highlighted(&target));
+        }
+        match config.theme {
+            Theme::Default => writeln!(out, "{}[{:>width$}] {}{}\x1b[0m  {}{}", color, score, ty, color, shown, tags)?,
+            Theme::MatchedOnly => writeln!(out, "[{:>width$}] {}  {}{}", score, ty, shown, tags)?,
+        }
+        if config.score_debug {
+            write_score_debug(out, &sp.path, config)?;
+        }
+        if let Some(cmp) = comparison {
+            let primary = cmp.primary[&sp.path];
+            match cmp.other.get(&sp.path) {
+                Some(&(rank, score)) => writeln!(out, "{}", paint(DIM, &format!("        compare: score={} rank={} delta={:+}",
+                    score, rank, rank as i64 - primary as i64), config.theme))?,
+                None => writeln!(out, "{}", paint(DIM, "        compare: no match", config.theme))?,
+            }
+        }
+        if let Some(cmd) = &config.preview {
+            write_preview(out, cmd, &sp.path, config.theme)?;
+        }
+    }
+    let removed = removed_paths(results, config);
+    if !removed.is_empty() {
+        writeln!(out, "{}", paint(DIM, "Removed since the previous run:", config.theme))?;
+        for path in removed {
+            writeln!(out, "{}", paint(DIM, &format!("  - {}", path), config.theme))?;
+        }
+    }
+    if results.is_empty() {
+        writeln!(out, "{}", paint("\x1b[1;31m", "No results found.", config.theme))?;
+    } else if config.show_counts {
+        writeln!(out, "{}", paint(DIM, &format!("Showing {} of {} matches", results.len(), total), config.theme))?;
     }
+    out.flush()
 }
 
-// Smith-Waterman local alignment for fuzzy matching, with big boosts for exact/prefix matches
-fn fuzzy_score(query: &str, candidate: &str) -> i32 {
-    let q: Vec<char> = query.chars().collect();
-    let c: Vec<char> = candidate.chars().collect();
-    let m = q.len();
-    let n = c.len();
-    if m == 0 || n == 0 {
-        return 0;
+// Small deterministic PRNG (SplitMix64) for --sample
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
     }
-    // Scoring scheme
-    let match_score = 2;
-    let mismatch_penalty = -1;
-    let gap_penalty = -2;
-    // DP matrix
-    let mut dp = vec![vec![0; n + 1]; m + 1];
-    let mut max_score = 0;
-    for i in 1..=m {
-        for j in 1..=n {
-            let score_diag = if q[i - 1] == c[j - 1] {
-                dp[i - 1][j - 1] + match_score
+
+    // Uniform in (0, 1]
+    fn next_unit(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Weighted reservoir sampling (Efraimidis-Spirakis): each match gets the key
+// u^(1/score) and the n largest keys win, so higher scores are likelier picks.
+// The picks are returned best score first.
+fn weighted_sample(matches: impl Iterator<Item = ScoredPath>, n: usize, seed: u64) -> Vec<ScoredPath> {
+    let mut rng = SplitMix64(seed);
+    let mut keyed: Vec<(f64, ScoredPath)> = matches
+        .map(|sp| (rng.next_unit().powf(1.0 / f64::from(sp.score.max(1))), sp))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut picked: Vec<ScoredPath> = keyed.into_iter().take(n).map(|(_, sp)| sp).collect();
+    picked.sort_by(|a, b| b.cmp(a));
+    picked
+}
+
+// Keep the matches scoring at least the median of `ranked` (sorted best first).
+// With an even count the upper of the two middle scores is the median, and
+// everything tied with it survives, so at least half the matches are kept.
+fn trim_below_median(ranked: &mut Vec<ScoredPath>) {
+    let Some(median) = ranked.get(ranked.len().saturating_sub(1) / 2).map(|sp| sp.score) else {
+        return;
+    };
+    ranked.retain(|sp| sp.score >= median);
+}
+
+// How a score is shown; ranking always uses the raw score. `index` is the
+// result's 0-based position in the shown list.
+fn display_score(score: i32, index: usize, transform: ScoreTransform) -> String {
+    match transform {
+        ScoreTransform::None => score.to_string(),
+        // Sign-preserving ln(1 + |score|), so 10000 and 8 end up near 9.2 and 2.2
+        ScoreTransform::Log => format!("{:.2}", f64::from(score).signum() * f64::from(score).abs().ln_1p()),
+        ScoreTransform::Rank => (index + 1).to_string(),
+    }
+}
+
+// Whether the path is, or is inside, a dot-named entry below the root.
+// shodh lists these by default; --mark-hidden points them out.
+fn is_hidden(path: &Path, config: &Config) -> bool {
+    let below_root = path.strip_prefix(&config.root).unwrap_or(path);
+    below_root.components().any(|c| match c {
+        std::path::Component::Normal(name) => name.to_str().is_some_and(|n| n.starts_with('.')),
+        _ => false,
+    })
+}
+
+fn write_json_result(
+    out: &mut dyn Write,
+    sp: &ScoredPath,
+    index: usize,
+    lines: &[Option<usize>],
+    comparison: Option<&Comparison>,
+    config: &Config,
+) -> io::Result<()> {
+    write!(out, "{{")?;
+    for (i, field) in config.json_fields.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        match field {
+            JsonField::Path => write!(out, "\"path\":{}", json_string(&sp.path.display().to_string()))?,
+            JsonField::Score => write!(out, "\"score\":{}", display_score(sp.score, index, config.score_transform))?,
+            JsonField::Type => write!(out, "\"type\":\"{}\"", if sp.path.is_dir() { "dir" } else { "file" })?,
+            JsonField::Hidden => write!(out, "\"hidden\":{}", is_hidden(&sp.path, config))?,
+            JsonField::Rank => write!(out, "\"rank\":{}", index + 1)?,
+            JsonField::Lines => match lines.get(index).copied().flatten() {
+                Some(n) => write!(out, "\"lines\":{}", n)?,
+                None => write!(out, "\"lines\":null")?,
+            },
+            JsonField::Change => match change_since(&sp.path, index, config) {
+                Some(Change::Added) => write!(out, "\"change\":\"added\"")?,
+                Some(Change::Moved(from)) => write!(out, "\"change\":\"moved\",\"previous_rank\":{}", from)?,
+                Some(Change::Same) | None => write!(out, "\"change\":\"same\"")?,
+            },
+            // Null when the --compare query doesn't match the path
+            JsonField::Compare => match comparison.and_then(|cmp| Some((cmp.primary[&sp.path], cmp.other.get(&sp.path)?))) {
+                Some((primary, &(rank, score))) => write!(out, "\"compare_score\":{},\"compare_rank\":{},\"rank_delta\":{}",
+                    score, rank, rank as i64 - primary as i64)?,
+                None => write!(out, "\"compare_score\":null,\"compare_rank\":null,\"rank_delta\":null")?,
+            },
+        }
+    }
+    write!(out, "}}")
+}
+
+// How a shown result differs from the --diff-against run
+enum Change {
+    Added,
+    // 1-based rank in the previous run
+    Moved(usize),
+    Same,
+}
+
+fn change_since(path: &Path, index: usize, config: &Config) -> Option<Change> {
+    let previous = config.diff_against.as_ref()?;
+    let shown = path.display().to_string();
+    Some(match previous.iter().position(|p| *p == shown) {
+        None => Change::Added,
+        Some(i) if i == index => Change::Same,
+        Some(i) => Change::Moved(i + 1),
+    })
+}
+
+// Paths of the --diff-against run that are not among the shown results
+fn removed_paths<'a>(results: &[ScoredPath], config: &'a Config) -> Vec<&'a str> {
+    let Some(previous) = &config.diff_against else {
+        return Vec::new();
+    };
+    let shown: HashSet<String> = results.iter().map(|sp| sp.path.display().to_string()).collect();
+    previous.iter().filter(|p| !shown.contains(*p)).map(|p| p.as_str()).collect()
+}
+
+// Collect the "path" of every result object in saved --format json or jsonl
+// output. Only as much JSON as those formats produce is understood.
+fn read_result_paths(text: &str) -> Result<Vec<String>, String> {
+    let mut reader = JsonReader { chars: text.chars().collect(), pos: 0 };
+    let mut paths = Vec::new();
+    reader.skip_ws();
+    let in_array = reader.eat('[');
+    loop {
+        reader.skip_ws();
+        match reader.peek() {
+            None if !in_array => break,
+            Some(']') if in_array => break,
+            Some(',') if in_array => { reader.pos += 1; continue; }
+            Some('{') => paths.extend(reader.object_paths()?),
+            Some(c) => return Err(format!("unexpected {:?} at offset {}", c, reader.pos)),
+            None => return Err("unterminated array".to_string()),
+        }
+    }
+    Ok(paths)
+}
+
+struct JsonReader {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonReader {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?} at offset {}", c, self.pos))
+        }
+    }
+
+    // The object's "path", or the paths of its "results" array for the
+    // --show-counts wrapper. Entries a diff marked "removed" weren't results
+    // of that run, so they are left out.
+    fn object_paths(&mut self) -> Result<Vec<String>, String> {
+        self.expect('{')?;
+        let mut paths = Vec::new();
+        let (mut path, mut removed) = (None, false);
+        self.skip_ws();
+        if self.eat('}') {
+            return Ok(paths);
+        }
+        loop {
+            self.skip_ws();
+            let key = self.string()?;
+            self.expect(':')?;
+            self.skip_ws();
+            if self.peek() == Some('"') {
+                let value = self.string()?;
+                match key.as_str() {
+                    "path" => path = Some(value),
+                    "change" => removed = value == "removed",
+                    _ => {}
+                }
+            } else if key == "results" && self.eat('[') {
+                loop {
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(']') => { self.pos += 1; break; }
+                        Some(',') => self.pos += 1,
+                        Some('{') => paths.extend(self.object_paths()?),
+                        Some(c) => return Err(format!("unexpected {:?} at offset {}", c, self.pos)),
+                        None => return Err("unterminated array".to_string()),
+                    }
+                }
             } else {
-                dp[i - 1][j - 1] + mismatch_penalty
+                // Numbers, booleans and null: nothing else is written
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                    self.pos += 1;
+                }
+            }
+            self.skip_ws();
+            if self.eat('}') {
+                if !removed {
+                    paths.extend(path);
+                }
+                return Ok(paths);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let esc = self.peek().ok_or("unterminated string")?;
+                    self.pos += 1;
+                    match esc {
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape at offset {}", self.pos))?;
+                            out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                            self.pos += 4;
+                        }
+                        other => out.push(other),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Block-buffer by default for pipe throughput; line-buffer for live consumers
+fn buffer_output<'a>(inner: impl Write + 'a, config: &Config) -> Box<dyn Write + 'a> {
+    if config.line_buffered {
+        Box::new(io::LineWriter::new(inner))
+    } else {
+        Box::new(io::BufWriter::new(inner))
+    }
+}
+
+// The buffered stdout, behind SimpleColor with --simple-color
+fn styled_output<'a>(buffered: &'a mut dyn Write, config: &Config) -> Box<dyn Write + 'a> {
+    if config.simple_color {
+        Box::new(SimpleColor(buffered))
+    } else {
+        Box::new(buffered)
+    }
+}
+
+// Rewrites the SGR escapes passing through to basic 8-color codes for
+// --simple-color: bright `1;3x` becomes `3x` and bold and dim are dropped
+struct SimpleColor<'a>(&'a mut dyn Write);
+
+impl Write for SimpleColor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    // Every escape shodh writes sits whole inside one buffer
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut rest = buf;
+        while let Some(start) = rest.windows(2).position(|w| w == b"\x1b[") {
+            let params = &rest[start + 2..];
+            let Some(len) = params.iter().position(|&b| !(b.is_ascii_digit() || b == b';')) else {
+                break;
             };
-            let score_up = dp[i - 1][j] + gap_penalty;
-            let score_left = dp[i][j - 1] + gap_penalty;
-            let score = 0.max(score_diag).max(score_up).max(score_left);
-            dp[i][j] = score;
-            if score > max_score {
-                max_score = score;
+            if params[len] != b'm' {
+                self.0.write_all(&rest[..start + 2 + len])?;
+                rest = &params[len..];
+                continue;
             }
+            self.0.write_all(&rest[..start])?;
+            let kept: Vec<&str> = std::str::from_utf8(&params[..len]).unwrap_or_default()
+                .split(';')
+                .filter(|p| p.is_empty() || *p == "0" || (p.len() == 2 && (p.starts_with('3') || p.starts_with('4'))))
+                .collect();
+            if !kept.is_empty() {
+                write!(self.0, "\x1b[{}m", kept.join(";"))?;
+            }
+            rest = &params[len + 1..];
         }
+        self.0.write_all(rest)
     }
-    // Boost for exact match
-    if query == candidate {
-        max_score += 10000;
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Write a path for machine consumers. On Unix these are the exact bytes of
+// the path, so non-UTF-8 names survive e.g. `xargs -0` unchanged.
+#[cfg(unix)]
+fn write_raw_path(out: &mut dyn Write, path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    out.write_all(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_raw_path(out: &mut dyn Write, path: &Path) -> io::Result<()> {
+    write!(out, "{}", path.display())
+}
+
+// Recursively walk the directory and collect all file and directory paths.
+// Errors below the root are skipped unless --strict-walk is set.
+// With --sorted-walk each directory's entries are visited in lexical order,
+// which matches `Path` ordering, so --resume-from can prune by comparison.
+// `.git` directories are skipped whole unless --include-git is set, and
+// special files unless --include-special is. Symlinks are always
+// candidates, but only descended into with --follow-symlinks.
+// With --same-filesystem, mount points are listed but not entered unless
+// allowed by --allow-mount. With --dedupe-hardlinks, only the first name
+// found for a file is a candidate. With --prune, directories that are
+// exact, near-exact or prefix matches are listed but not entered.
+// Stops once more than --walk-max-entries candidates were found.
+// A directory is handed to `walk` after its children, once its entry count
+// is known.
+// Returns the number of immediate entries of `path` (0 for files).
+fn walk_dir(path: &Path, config: &Config, walk: &mut Walk) -> Result<usize, String> {
+    let meta = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut count = 0;
+    if meta.is_dir() {
+        let mut entries = fs::read_dir(path)
+            .and_then(|entries| entries.map(|e| e.map(|e| (e.path(), e.file_type().ok()))).collect::<io::Result<Vec<_>>>())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if config.sorted_walk {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        for (p, file_type) in entries {
+            if walk.seen > config.walk_max_entries {
+                break;
+            }
+            count += 1;
+            if !config.include_git && p.file_name().is_some_and(|n| n == ".git") && p.is_dir() {
+                continue;
+            }
+            if !config.include_special && file_type.is_some_and(is_special) {
+                continue;
+            }
+            if config.dedupe_hardlinks && file_type.is_some_and(|t| t.is_file()) && !first_link(&p, &mut walk.inodes) {
+                continue;
+            }
+            // At or before the resume point: only descend into its ancestors
+            if let Some(resume) = config.resume_from.as_deref().filter(|r| p.as_path() <= *r) {
+                if resume.starts_with(&p) && p.is_dir() {
+                    match walk_dir(&p, config, walk) {
+                        Err(e) if config.strict_walk => return Err(e),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            let mut entries = None;
+            let descend = config.follow_symlinks || !file_type.is_some_and(|t| t.is_symlink());
+            // --files-only would hide the pruned directory and everything under it
+            let pruned = config.prune && !config.files_only && is_anchored(&p, config);
+            if descend && !pruned && p.is_dir() && !(config.same_filesystem && crosses_mount(&meta, &p, config)) {
+                match walk_dir(&p, config, walk) {
+                    Ok(n) => entries = Some(n),
+                    Err(e) if config.strict_walk => return Err(e),
+                    Err(_) => {}
+                }
+            }
+            walk.push(Candidate { path: p, entries }, config);
+        }
+    } else {
+        walk.push(Candidate { path: path.to_path_buf(), entries: None }, config);
+    }
+    Ok(count)
+}
+
+// Whether the entry itself (not a symlink's target, like `find -user`) has
+// the --owner uid and --group gid
+#[cfg(unix)]
+fn owned_by(path: &Path, config: &Config) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    config.owner.is_none_or(|uid| meta.uid() == uid) && config.group.is_none_or(|gid| meta.gid() == gid)
+}
+
+#[cfg(not(unix))]
+fn owned_by(_path: &Path, _config: &Config) -> bool {
+    true
+}
+
+// A numeric id, or the id of `name` in a passwd or group file, whose lines
+// start with `name:password:id:`. Accounts only known to NSS services like
+// LDAP need to be given by number.
+fn resolve_id(value: &str, database: &str, flag: &str) -> Result<u32, String> {
+    if let Ok(id) = value.parse() {
+        return Ok(id);
     }
-    // Boost for prefix match
-    if candidate.starts_with(query) && query != candidate {
-        max_score += 5000;
+    let text = fs::read_to_string(database).map_err(|e| format!("{}: {}", database, e))?;
+    text.lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            (name == value).then(|| fields.nth(1)?.parse().ok())?
+        })
+        .ok_or_else(|| format!("Unknown name for {}: {} (not in {})", flag, value, database))
+}
+
+// FIFOs, sockets and device nodes. The type comes from the directory entry,
+// so a symlink to one is still an ordinary candidate.
+#[cfg(unix)]
+fn is_special(file_type: fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_fifo() || file_type.is_socket() || file_type.is_block_device() || file_type.is_char_device()
+}
+
+#[cfg(not(unix))]
+fn is_special(_file_type: fs::FileType) -> bool {
+    false
+}
+
+// Whether `path` is the first name seen for its inode. Files with a single
+// link can't repeat, so only multiply linked ones are remembered.
+#[cfg(unix)]
+fn first_link(path: &Path, inodes: &mut HashSet<(u64, u64)>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.nlink() > 1 => inodes.insert((meta.dev(), meta.ino())),
+        _ => true,
     }
-    max_score
-} 
\ No newline at end of file
+}
+
+#[cfg(not(unix))]
+fn first_link(_path: &Path, _inodes: &mut HashSet<(u64, u64)>) -> bool {
+    true
+}
+
+// Whether `dir` is on another device than its parent and not allowlisted
+#[cfg(unix)]
+fn crosses_mount(parent: &fs::Metadata, dir: &Path, config: &Config) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(meta) = fs::metadata(dir) else {
+        return false;
+    };
+    skips_device(parent.dev(), meta.dev(), || fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()), config)
+}
+
+// The device check itself; the canonical path is only resolved for another
+// device, to look it up in --allow-mount
+#[cfg(unix)]
+fn skips_device(parent_dev: u64, dev: u64, canonical: impl FnOnce() -> PathBuf, config: &Config) -> bool {
+    dev != parent_dev && !config.allow_mounts.contains(&canonical())
+}
+
+#[cfg(not(unix))]
+fn crosses_mount(_parent: &fs::Metadata, _dir: &Path, _config: &Config) -> bool {
+    false
+}
+
+// First phase of --scope-dir: keep only candidates below a directory whose
+// name contains every char of the scope query in order
+fn restrict_to_scope(candidates: Vec<Candidate>, scope: &str, config: &Config) -> Vec<Candidate> {
+    let scope_dirs: HashSet<PathBuf> = candidates.iter()
+        .filter(|c| c.path.is_dir())
+        .filter(|c| {
+            let Some(name) = c.path.file_name().and_then(|n| n.to_str()) else {
+                return false;
+            };
+            let (scope, name) = prepare(scope, name, config);
+            fits_max_gap(&scope, &name, usize::MAX)
+        })
+        .map(|c| c.path.clone())
+        .collect();
+    candidates.into_iter()
+        .filter(|c| c.path.ancestors().skip(1).any(|a| scope_dirs.contains(a)))
+        .collect()
+}
+
+fn filter_and_score(candidate: &Candidate, query: &str, config: &Config) -> Option<ScoredPath> {
+    let path = &candidate.path;
+    // Type filtering
+    if config.files_only && !path.is_file() {
+        return None;
+    }
+    if config.dirs_only && !path.is_dir() {
+        return None;
+    }
+    // Dotfiles like .bashrc count as having no extension
+    if config.has_ext.is_some_and(|want| path.extension().is_some() != want) && !path.is_dir() {
+        return None;
+    }
+    // Child count filtering; unreadable directories have no count and are dropped
+    if config.min_entries.is_some() || config.max_entries.is_some() {
+        let n = candidate.entries?;
+        if config.min_entries.is_some_and(|min| n < min) || config.max_entries.is_some_and(|max| n > max) {
+            return None;
+        }
+    }
+    if config.min_components.is_some() || config.max_components.is_some() {
+        let n = component_count(path);
+        if config.min_components.is_some_and(|min| n < min) || config.max_components.is_some_and(|max| n > max) {
+            return None;
+        }
+    }
+    let score = score_name(query, &scored_name(path, query, config)?, config);
+    // After the cap, so path boosts still reorder capped matches
+    let score = score?.saturating_add(path_boost(path, config));
+    // Ownership needs a stat, so only matches are checked
+    if (config.owner.is_some() || config.group.is_some()) && !owned_by(path, config) {
+        return None;
+    }
+    let mtime = match config.tiebreak {
+        TieBreak::Mtime => fs::metadata(path).and_then(|m| m.modified()).ok(),
+        TieBreak::Path => None,
+    };
+    Some(ScoredPath { score, path: path.clone(), mtime })
+}
+
+// Sum of the --boost-path boosts whose substring the path contains
+fn path_boost(path: &Path, config: &Config) -> i32 {
+    if config.path_boosts.is_empty() {
+        return 0;
+    }
+    let shown = path.to_string_lossy();
+    config.path_boosts.iter()
+        .filter(|(part, _)| shown.contains(part.as_str()))
+        .fold(0, |sum, (_, boost)| sum.saturating_add(*boost))
+}
+
+// Apply the name filters and score one name; None if it doesn't match
+fn score_name(query: &str, name: &str, config: &Config) -> Option<i32> {
+    let (query, candidate) = prepare(query, name, config);
+    // A plain ends_with rejects most names before the alignment DP runs
+    if config.suffix && !candidate.ends_with(&query) {
+        return None;
+    }
+    if config.max_gap.is_some_and(|gap| !fits_max_gap(&query, &candidate, gap)) {
+        return None;
+    }
+    // The same comparison that earns the exact boost
+    if config.not_exact && candidate == query {
+        return None;
+    }
+    Some(fuzzy_score(&query, &candidate, config.scoring)).filter(|&score| score > 0)
+}
+
+// Where a symlink points, as stored in the link; None for anything else.
+// Broken links still have a target.
+fn link_target(path: &Path) -> Option<PathBuf> {
+    fs::read_link(path).ok()
+}
+
+// The name a path is scored by: its own, or under --match-target-name its
+// link target's when that scores higher. Whatever re-scores or classifies a
+// result goes through this, so it agrees with the ranking.
+fn scored_name(path: &Path, query: &str, config: &Config) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    if config.match_target_name && let Some(target) = link_target(path)
+        && let Some(target_name) = target.file_name().and_then(|n| n.to_str())
+        && score_name(query, target_name, config) > score_name(query, name, config) {
+        return Some(target_name.to_string());
+    }
+    Some(name.to_string())
+}
+
+// Components of the path as displayed, root included; `.` and a leading `/`
+// or drive prefix don't count, so `./src/main.rs` and `/src/main.rs` have 2
+fn component_count(path: &Path) -> usize {
+    path.components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::ParentDir))
+        .count()
+}
+
+// Apply the configured normalization and case folding to a query and a candidate name
+fn prepare(query: &str, name: &str, config: &Config) -> (String, String) {
+    // Unicode normalization, so NFD names (e.g. on macOS) match NFC queries
+    let (query, name) = (normalize(query, config.normalize), normalize(name, config.normalize));
+    // Naming convention, so MyClass, my_class and my-class compare equal
+    let (query, name) = (transform_case(&query, config.transform), transform_case(&name, config.transform));
+    // Case sensitivity
+    match config.case {
+        CaseSensitivity::Insensitive => (query.to_lowercase(), name.to_lowercase()),
+        CaseSensitivity::Sensitive => (query, name),
+    }
+}
+
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+
+// `text` in the SGR color `code`, or plain with --theme matched-only, which
+// only highlights the matched chars
+fn paint(code: &str, text: &str, theme: Theme) -> String {
+    match theme {
+        Theme::Default => format!("{}{}\x1b[0m", code, text),
+        Theme::MatchedOnly => text.to_string(),
+    }
+}
+
+// The path with the chars of its name that the alignment matched in bold,
+// if its name is `name`, the one the result was scored by. Names whose
+// prepared form has a different length can't be mapped back, so they are
+// left plain.
+fn highlight_matches(path: &Path, name: &str, config: &Config) -> String {
+    let shown = path.display().to_string();
+    if path.file_name().and_then(|n| n.to_str()) != Some(name) {
+        return shown;
+    }
+    let (query, candidate) = prepare(&config.query, name, config);
+    if candidate.chars().count() != name.chars().count() || !shown.ends_with(name) {
+        return shown;
+    }
+    let positions = match_positions(&query, &candidate);
+    let mut out = shown[..shown.len() - name.len()].to_string();
+    for (i, ch) in name.chars().enumerate() {
+        if positions.contains(&i) {
+            out.push_str("\x1b[1m");
+            out.push(ch);
+            out.push_str("\x1b[0m");
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+// Deepest directory containing every result, for --collapse-prefix. Needs
+// two results, so a lone one keeps its full path.
+fn common_dir(results: &[ScoredPath]) -> Option<PathBuf> {
+    let [first, rest @ ..] = results else {
+        return None;
+    };
+    if rest.is_empty() {
+        return None;
+    }
+    let mut prefix = first.path.parent()?.to_path_buf();
+    for sp in rest {
+        while !sp.path.starts_with(&prefix) {
+            if !prefix.pop() {
+                return None;
+            }
+        }
+    }
+    (!prefix.as_os_str().is_empty()).then_some(prefix)
+}
+
+// The separator shown after directory results unless --no-slash
+fn dir_suffix(path: &Path, config: &Config) -> &'static str {
+    if config.dir_slash && path.is_dir() {
+        std::path::MAIN_SEPARATOR_STR
+    } else {
+        ""
+    }
+}
+
+// POSIX single quoting: everything is literal inside '...', and a quote
+// itself is closed, escaped and reopened as '\''
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Run a --preview command with `{}` replaced by the quoted path and print the
+// start of its stdout. A failing command is reported inline, not as an error.
+fn write_preview(out: &mut dyn Write, cmd: &str, path: &Path, theme: Theme) -> io::Result<()> {
+    let shown = path.display().to_string();
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", &cmd.replace("{}", &format!("\"{}\"", shown))]).output()
+    } else {
+        Command::new("sh").args(["-c", &cmd.replace("{}", &shell_quote(&shown))]).output()
+    };
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => return writeln!(out, "{}", paint(DIM, &format!("        preview failed: {}", output.status), theme)),
+        Err(e) => return writeln!(out, "{}", paint(DIM, &format!("        preview failed: {}", e), theme)),
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut lines = text.lines();
+    for line in lines.by_ref().take(PREVIEW_MAX_LINES) {
+        writeln!(out, "{}", paint(DIM, &format!("        │ {}", line), theme))?;
+    }
+    if lines.next().is_some() {
+        writeln!(out, "{}", paint(DIM, "        │ …", theme))?;
+    }
+    Ok(())
+}
+
+// Start one `sh -c CMD` and write the result paths to its stdin like
+// --format path does, or NUL-terminated with -0, then wait for it. A command
+// that exits before reading everything (e.g. `head`) is not an error.
+fn pipe_results(cmd: &str, results: &[ScoredPath], config: &Config) -> Result<(), String> {
+    let child = if cfg!(windows) {
+        Command::new("cmd").args(["/C", cmd]).stdin(Stdio::piped()).spawn()
+    } else {
+        Command::new("sh").args(["-c", cmd]).stdin(Stdio::piped()).spawn()
+    };
+    let mut child = child.map_err(|e| format!("could not run {}: {}", cmd, e))?;
+    let mut stdin = io::BufWriter::new(child.stdin.take().expect("stdin is piped"));
+    let written = results.iter()
+        .try_for_each(|sp| {
+            write_raw_path(&mut stdin, &sp.path)?;
+            match config.format {
+                OutputFormat::Nul => stdin.write_all(b"\0"),
+                _ => writeln!(stdin, "{}", dir_suffix(&sp.path, config)),
+            }
+        })
+        .and_then(|()| stdin.flush());
+    // Closing stdin lets the command see the end of the list
+    drop(stdin);
+    if let Err(e) = written && e.kind() != io::ErrorKind::BrokenPipe {
+        return Err(format!("writing to {}: {}", cmd, e));
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{}: {}", cmd, status)),
+        Err(e) => Err(format!("could not wait for {}: {}", cmd, e)),
+    }
+}
+
+// Run $VISUAL, falling back to $EDITOR, on `path` and wait for it. The
+// variable goes through the shell, so values like `code -w` work.
+fn open_in_editor(path: &Path) -> Result<(), String> {
+    let editor = ["VISUAL", "EDITOR"].iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .ok_or("--open needs $VISUAL or $EDITOR to be set")?;
+    let shown = path.display().to_string();
+    let status = if cfg!(windows) {
+        Command::new("cmd").args(["/C", &format!("{} \"{}\"", editor, shown)]).status()
+    } else {
+        Command::new("sh").args(["-c", &format!("{} {}", editor, shell_quote(&shown))]).status()
+    };
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} {}: {}", editor, shown, status)),
+        Err(e) => Err(format!("could not run {}: {}", editor, e)),
+    }
+}
+
+// Lines in a regular file, counting a last line without a newline. None for
+// directories, unreadable files and binary ones (any NUL byte).
+fn count_lines(path: &Path) -> Option<usize> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut file = fs::File::open(path).ok()?;
+    let mut buf = [0u8; 64 * 1024];
+    let (mut lines, mut last) = (0, b'\n');
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        };
+        let chunk = &buf[..n];
+        if chunk.contains(&0) {
+            return None;
+        }
+        lines += chunk.iter().filter(|&&b| b == b'\n').count();
+        last = chunk[n - 1];
+    }
+    Some(lines + usize::from(last != b'\n'))
+}
+
+// Whether a match earned the exact, near-exact or prefix boost: the first
+// tier for --exact-first
+fn is_anchored(path: &Path, config: &Config) -> bool {
+    let Some(name) = scored_name(path, &config.query, config) else {
+        return false;
+    };
+    let (query, candidate) = prepare(&config.query, &name, config);
+    query == candidate || is_near_exact(&query, &candidate) || candidate.starts_with(&query)
+}
+
+// Unstable: the component names and layout may change between releases
+fn write_score_debug(out: &mut dyn Write, path: &Path, config: &Config) -> io::Result<()> {
+    let Some(name) = scored_name(path, &config.query, config) else {
+        return Ok(());
+    };
+    let (query, candidate) = prepare(&config.query, &name, config);
+    writeln!(out, "{}", paint(DIM, &format!("        query={:?} candidate={:?}", query, candidate), config.theme))?;
+    // The same list --why-above compares, so the total is the shown score
+    let Ok(components) = score_components(path, config) else {
+        return Ok(());
+    };
+    let total = components.iter().fold(0, |sum: i32, (_, n)| sum.saturating_add(*n));
+    let fields: Vec<String> = components.iter().map(|(name, n)| format!("{}={}", name, n)).collect();
+    writeln!(out, "{}", paint(DIM, &format!("        {} total={}", fields.join(" "), total), config.theme))
+}
+
+fn normalize(s: &str, form: Normalization) -> String {
+    match form {
+        Normalization::Nfc => s.nfc().collect(),
+        Normalization::Nfd => s.nfd().collect(),
+        Normalization::None => s.to_string(),
+    }
+}
+
+// Whether every query char occurs in order in the candidate with at most
+// `max_gap` candidate chars between consecutive matches
+fn fits_max_gap(query: &str, candidate: &str, max_gap: usize) -> bool {
+    let c: Vec<char> = candidate.chars().collect();
+    // reach[j]: the query prefix so far can end with its last char at c[j]
+    let mut reach: Option<Vec<bool>> = None;
+    for qc in query.chars() {
+        let next: Vec<bool> = match &reach {
+            None => c.iter().map(|&ch| ch == qc).collect(),
+            Some(prev) => {
+                let mut last = None;
+                let mut next = vec![false; c.len()];
+                for j in 0..c.len() {
+                    if c[j] == qc && last.is_some_and(|l: usize| j - l - 1 <= max_gap) {
+                        next[j] = true;
+                    }
+                    if prev[j] {
+                        last = Some(j);
+                    }
+                }
+                next
+            }
+        };
+        if !next.contains(&true) {
+            return false;
+        }
+        reach = Some(next);
+    }
+    true
+}
+
+// Individual parts of a fuzzy score, summed by `total`
+#[derive(Debug, Default, Clone, Copy)]
+struct ScoreBreakdown {
+    alignment: i32,
+    exact_boost: i32,
+    near_exact_boost: i32,
+    prefix_boost: i32,
+}
+
+impl ScoreBreakdown {
+    fn total(&self) -> i32 {
+        self.alignment + self.exact_boost + self.near_exact_boost + self.prefix_boost
+    }
+}
+
+fn fuzzy_score(query: &str, candidate: &str, scoring: ScoringConfig) -> i32 {
+    let total = fuzzy_breakdown(query, candidate, scoring).total();
+    scoring.cap.map_or(total, |cap| total.min(cap))
+}
+
+// Split an identifier into words on `_`, `-`, spaces and camelCase boundaries
+fn split_words(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &ch) in chars.iter().enumerate() {
+        if ch == '_' || ch == '-' || ch == ' ' {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if ch.is_uppercase() && i > 0 && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|c| c.is_lowercase());
+            // "myClass" -> my|Class, "HTTPServer" -> HTTP|Server
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.push(ch);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn transform_case(s: &str, transform: QueryTransform) -> String {
+    let lower_words = || -> Vec<String> { split_words(s).iter().map(|w| w.to_lowercase()).collect() };
+    match transform {
+        QueryTransform::None => s.to_string(),
+        QueryTransform::Snake => lower_words().join("_"),
+        QueryTransform::Kebab => lower_words().join("-"),
+        QueryTransform::Camel => {
+            let mut out = String::new();
+            for (i, w) in lower_words().iter().enumerate() {
+                let mut chars = w.chars();
+                match chars.next() {
+                    Some(first) if i > 0 => {
+                        out.extend(first.to_uppercase());
+                        out.push_str(chars.as_str());
+                    }
+                    _ => out.push_str(w),
+                }
+            }
+            out
+        }
+    }
+}
+
+// Scoring scheme for the alignment
+const MATCH_SCORE: i32 = 2;
+const MISMATCH_PENALTY: i32 = -1;
+const GAP_PENALTY: i32 = -2;
+const EXACT_BOOST: i32 = 10000;
+const DEFAULT_NEAR_EXACT_BOOST: i32 = 7500;
+const PREFIX_BOOST: i32 = 5000;
+// Best possible --rank density score
+const DENSITY_SCALE: i32 = 1000;
+// Longest name most filesystems allow (NAME_MAX)
+const MAX_NAME_LEN: i32 = 255;
+
+// Exact matches must outrank prefix matches, and prefix matches any other
+// match, whatever the alignment of names up to MAX_NAME_LEN chars
+const _: () = assert!(MATCH_SCORE * MAX_NAME_LEN < PREFIX_BOOST);
+const _: () = assert!(PREFIX_BOOST + MATCH_SCORE * MAX_NAME_LEN < EXACT_BOOST);
+const _: () = assert!(DENSITY_SCALE < PREFIX_BOOST && PREFIX_BOOST + DENSITY_SCALE < EXACT_BOOST);
+
+// Smith-Waterman DP matrix, with the cell holding the best local score
+fn alignment_matrix(q: &[char], c: &[char]) -> (Vec<Vec<i32>>, (usize, usize)) {
+    let m = q.len();
+    let n = c.len();
+    let mut dp = vec![vec![0; n + 1]; m + 1];
+    let mut best = (0, 0);
+    for i in 1..=m {
+        for j in 1..=n {
+            let score_diag = if q[i - 1] == c[j - 1] {
+                dp[i - 1][j - 1] + MATCH_SCORE
+            } else {
+                dp[i - 1][j - 1] + MISMATCH_PENALTY
+            };
+            let score_up = dp[i - 1][j] + GAP_PENALTY;
+            let score_left = dp[i][j - 1] + GAP_PENALTY;
+            let score = 0.max(score_diag).max(score_up).max(score_left);
+            dp[i][j] = score;
+            if score > dp[best.0][best.1] {
+                best = (i, j);
+            }
+        }
+    }
+    (dp, best)
+}
+
+// Candidate char indices that the best local alignment matched to the query
+fn match_positions(query: &str, candidate: &str) -> Vec<usize> {
+    let q: Vec<char> = query.chars().collect();
+    let c: Vec<char> = candidate.chars().collect();
+    let (dp, best) = alignment_matrix(&q, &c);
+    traceback(&q, &c, &dp, best)
+}
+
+fn traceback(q: &[char], c: &[char], dp: &[Vec<i32>], (mut i, mut j): (usize, usize)) -> Vec<usize> {
+    let mut positions = Vec::new();
+    // Trace back from the best cell until the local alignment starts
+    while i > 0 && j > 0 && dp[i][j] > 0 {
+        if q[i - 1] == c[j - 1] && dp[i][j] == dp[i - 1][j - 1] + MATCH_SCORE {
+            positions.push(j - 1);
+            i -= 1;
+            j -= 1;
+        } else if dp[i][j] == dp[i - 1][j - 1] + MISMATCH_PENALTY {
+            i -= 1;
+            j -= 1;
+        } else if dp[i][j] == dp[i - 1][j] + GAP_PENALTY {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
+    positions.reverse();
+    positions
+}
+
+// Exact but for case, or for the candidate's last extension, e.g. `main`
+// for `Main.rs`. Only the candidate loses its extension, so `main.rs` is not
+// near `main.py`.
+fn is_near_exact(query: &str, candidate: &str) -> bool {
+    let query = query.to_lowercase();
+    query != candidate && (query == candidate.to_lowercase() || query == fold_stem(candidate))
+}
+
+// A name without its last extension, lowercased; dotfiles keep their name
+fn fold_stem(name: &str) -> String {
+    let stem = match name.rfind('.') {
+        Some(dot) if dot > 0 => &name[..dot],
+        _ => name,
+    };
+    stem.to_lowercase()
+}
+
+// Matched chars per char of span, 0..=DENSITY_SCALE. Scaled by the share of
+// the query that matched, so a single matching char isn't perfectly dense.
+fn match_density(positions: &[usize], query_len: usize) -> i32 {
+    let (Some(&first), Some(&last)) = (positions.first(), positions.last()) else {
+        return 0;
+    };
+    let matched = positions.len() as i64;
+    let span = (last - first + 1) as i64;
+    (DENSITY_SCALE as i64 * matched * matched / (span * query_len as i64)) as i32
+}
+
+// Smith-Waterman local alignment for fuzzy matching, with big boosts for exact/prefix matches
+fn fuzzy_breakdown(query: &str, candidate: &str, scoring: ScoringConfig) -> ScoreBreakdown {
+    let ranking = scoring.ranking;
+    let q: Vec<char> = query.chars().collect();
+    let c: Vec<char> = candidate.chars().collect();
+    if q.is_empty() || c.is_empty() {
+        return ScoreBreakdown::default();
+    }
+    let (dp, (i, j)) = alignment_matrix(&q, &c);
+    let alignment = match ranking {
+        Ranking::Alignment => dp[i][j],
+        Ranking::Density => match_density(&traceback(&q, &c, &dp, (i, j)), q.len()),
+    };
+    let mut breakdown = ScoreBreakdown { alignment, ..Default::default() };
+    // Each aligned char adds at most MATCH_SCORE, and cells never go negative
+    debug_assert!(ranking != Ranking::Alignment
+        || (0..=MATCH_SCORE * q.len().min(c.len()) as i32).contains(&breakdown.alignment));
+    // Boost for exact match
+    if query == candidate {
+        breakdown.exact_boost = EXACT_BOOST;
+    }
+    // Boost for a match that is exact but for case or extension, e.g. `main`
+    // for `Main.rs`; it stands in for the prefix boost rather than adding to it
+    if is_near_exact(query, candidate) {
+        breakdown.near_exact_boost = scoring.near_exact_boost;
+    } else if candidate.starts_with(query) && query != candidate {
+        // Boost for prefix match
+        breakdown.prefix_boost = PREFIX_BOOST;
+    }
+    breakdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn scoring(ranking: Ranking) -> ScoringConfig {
+        ScoringConfig { ranking, near_exact_boost: DEFAULT_NEAR_EXACT_BOOST, cap: None }
+    }
+
+    fn rankings() -> impl Strategy<Value = Ranking> {
+        prop_oneof![Just(Ranking::Alignment), Just(Ranking::Density)]
+    }
+
+    // A fresh directory holding `files` (relative paths, parents created)
+    fn fixture(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        }
+        dir
+    }
+
+    // `n` files all matching `main`, with distinct scores spread out
+    fn many_mains(n: usize) -> tempfile::TempDir {
+        let names: Vec<String> = (0..n).map(|i| format!("m{}ain{}", "x".repeat(i % 7), i)).collect();
+        fixture(&names.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    fn config(args: &[&str]) -> Config {
+        let args = std::iter::once("shodh").chain(args.iter().copied()).map(String::from).collect();
+        Config::parse(args).unwrap()
+    }
+
+    // What a search with `config` prints on stdout
+    fn output(config: &Config) -> String {
+        let search = search(config).unwrap();
+        let results = select_results(&search.ranked, config);
+        let mut out = Vec::new();
+        write_results(&mut out, &results, search.total, search.comparison.as_ref(), config).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // Matched-only output with the match highlights taken out
+    fn unhighlighted(config: &Config) -> String {
+        output(config).replace("\x1b[1m", "").replace("\x1b[0m", "")
+    }
+
+    // Every match, best first, relative to the config's root
+    fn found(config: &Config) -> Vec<String> {
+        search(config).unwrap().ranked.iter()
+            .map(|sp| sp.path.strip_prefix(&config.root).unwrap().display().to_string())
+            .collect()
+    }
+
+    // Score of `name` for the config's query, as filtering and ranking see it
+    fn name_score(name: &str, config: &Config) -> i32 {
+        score_name(&config.query, name, config).unwrap_or(0)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strict_walk_fails_on_an_unreadable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = fixture(&["free/main", "locked/main"]);
+        let root = dir.path().to_str().unwrap();
+        let locked = dir.path().join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(&locked).is_ok();
+        let lenient = found(&config(&["main", root]));
+        let strict = search(&config(&["main", root, "--strict-walk"]));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root reads it anyway, so there is no error to see
+        if readable {
+            return;
+        }
+        assert_eq!(lenient, ["free/main"]);
+        let error = strict.err().unwrap();
+        assert!(error.contains("locked") && error.contains("ermission denied"), "{}", error);
+    }
+
+    #[test]
+    fn near_exact_scores_between_exact_and_fuzzy() {
+        for args in [&["main"][..], &["main", "-s"], &["main", "--rank", "density"]] {
+            let config = config(args);
+            let (exact, near, prefix, fuzzy) =
+                (name_score("main", &config), name_score("Main.rs", &config), name_score("mainframe.c", &config), name_score("my_ain", &config));
+            assert!(exact > near && near > prefix && prefix > fuzzy && fuzzy > 0, "{:?}: {} {} {} {}", args, exact, near, prefix, fuzzy);
+        }
+        // Case-sensitive: only the case differs
+        let config = config(&["main", "-s"]);
+        assert!(name_score("main", &config) > name_score("MAIN", &config));
+        assert!(name_score("MAIN", &config) > name_score("mainframe", &config));
+    }
+
+    #[test]
+    fn near_exact_never_strips_the_query_extension() {
+        let config = config(&["main.rs"]);
+        assert!(!is_near_exact("main.rs", "main.py"));
+        assert!(name_score("main.py", &config) < PREFIX_BOOST);
+        assert!(name_score("main.rs.bak", &config) > PREFIX_BOOST);
+    }
+
+    #[test]
+    fn near_exact_boost_stays_above_the_prefix_boost() {
+        let parse = |boost: &str| Config::parse(["shodh", "main", "--near-exact-boost", boost].map(String::from).to_vec());
+        assert!(parse("0").is_err());
+        assert!(parse(&(PREFIX_BOOST - 1).to_string()).is_err());
+        assert!(parse(&EXACT_BOOST.to_string()).is_err());
+        let config = parse(&PREFIX_BOOST.to_string()).unwrap();
+        assert!(name_score("main.rs", &config) >= name_score("mainframe.c", &config));
+        assert!(is_anchored(Path::new("main.rs"), &config));
+        assert!(!is_anchored(Path::new("my_ain"), &config));
+    }
+
+    #[test]
+    fn calibrate_sees_every_match_under_limit_memory() {
+        let dir = many_mains(40);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--limit-memory", "0", "-n", "3", "--calibrate"]);
+        assert!(!can_stream(&config));
+        let search = search(&config).unwrap();
+        assert_eq!(search.ranked.len(), 40);
+        assert_eq!(search.total, 40);
+    }
+
+    #[test]
+    fn match_report_covers_every_match_under_limit_memory() {
+        let dir = many_mains(40);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--limit-memory", "0", "-n", "2", "--match-report"]);
+        assert!(!can_stream(&config));
+        let search = search(&config).unwrap();
+        let report = MatchReport::new(&search.ranked, &config);
+        assert_eq!(report.matches, 40);
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with("{\"matches\":40,"));
+    }
+
+    #[test]
+    fn per_tier_caps_each_score() {
+        let dir = fixture(&["x1/main", "y2/main", "z3/main", "main_x", "mxain"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--per-tier", "1"]);
+        let search = search(&config).unwrap();
+        let results = select_results(&search.ranked, &config);
+        let scores: Vec<i32> = results.iter().map(|sp| sp.score).collect();
+        assert_eq!(search.ranked.iter().filter(|sp| sp.score == scores[0]).count(), 3);
+        assert_eq!(scores.len(), 3);
+        assert!(scores.windows(2).all(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn show_counts_reaches_json_output() {
+        let dir = many_mains(5);
+        let root = dir.path().to_str().unwrap();
+        let json = output(&config(&["main", root, "-n", "2", "--format", "json", "--show-counts"]));
+        assert!(json.starts_with("{\"total\":5,\"shown\":2,\"results\":[{"), "{}", json);
+        assert!(json.ends_with("]}\n"), "{}", json);
+        // --diff-against still reads the wrapped results
+        assert_eq!(read_result_paths(&json).unwrap().len(), 2);
+        let jsonl = output(&config(&["main", root, "-n", "2", "--format", "jsonl", "--show-counts"]));
+        assert_eq!(jsonl.lines().last(), Some("{\"total\":5,\"shown\":2}"));
+        assert_eq!(read_result_paths(&jsonl).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn compare_reaches_json_output() {
+        let dir = fixture(&["main.rs", "maintain.rs", "readme"]);
+        let root = dir.path().to_str().unwrap();
+        let json = output(&config(&["main", root, "--format", "jsonl", "--compare", "rs"]));
+        assert!(json.lines().all(|line| line.contains("\"compare_rank\":")), "{}", json);
+        let config = config(&["main", root, "--format", "json", "--json-fields", "path,compare", "--compare", "qqzz"]);
+        assert!(output(&config).contains("\"compare_score\":null,\"compare_rank\":null,\"rank_delta\":null"));
+        assert!(Config::parse(["shodh", "main", "--json-fields", "compare"].map(String::from).to_vec()).is_err());
+    }
+
+    #[test]
+    fn list_filters_prints_json() {
+        let config = config(&["main", "--list-filters", "--format", "json", "--allow-mount", "/mnt"]);
+        let mut out = Vec::new();
+        write_filters(&mut out, &config).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with("{\"query\":\"main\","), "{}", json);
+        assert!(json.contains("\"allow\":[\"/mnt\"]"), "{}", json);
+        assert!(json.ends_with("}\n"), "{}", json);
+    }
+
+    #[test]
+    fn matched_only_theme_prints_just_the_match_highlights() {
+        let dir = fixture(&["main.rs", "src/my_ain.c", "mxain"]);
+        let root = dir.path().to_str().unwrap();
+        let previous = dir.path().join("previous.json");
+        fs::write(&previous, "[{\"path\":\"gone\"}]").unwrap();
+        let previous = previous.to_str().unwrap();
+        let printed = [
+            output(&config(&["main", root, "--theme", "matched-only", "--show-counts", "--score-debug", "--compare", "ain",
+                "--exact-first", "--diff-against", previous, "--mark-hidden", "--preview", "echo hi", "--collapse-prefix"])),
+            output(&config(&["qqzz", root, "--theme", "matched-only"])),
+        ].concat();
+        for part in printed.split("\x1b[").skip(1) {
+            assert!(part.starts_with("1m") || part.starts_with("0m"), "{:?}", printed);
+        }
+        assert!(printed.contains("\x1b[1m"));
+        assert!(printed.contains("fuzzy matches:") && printed.contains("Removed since") && printed.contains("No results found."));
+        assert!(printed.contains("│ hi") && printed.contains("compare: ") && printed.contains("alignment="));
+    }
+
+    #[test]
+    fn resume_from_is_relative_to_the_root() {
+        let dir = fixture(&["a/x1", "a/x2", "a/x3", "b/x4"]);
+        let root = dir.path().to_str().unwrap();
+        let names = |config: &Config| {
+            let mut names = found(config);
+            names.sort();
+            names
+        };
+        let second_page = vec!["a/x3".to_string(), "b/x4".to_string()];
+        for resume in ["a/x2", "./a/x2", &format!("{}/a/x2", root)] {
+            assert_eq!(names(&config(&["x", root, "--resume-from", resume])), second_page, "{}", resume);
+        }
+        assert_eq!(resolve_resume(Path::new("a/x2"), Path::new(".")).unwrap(), Path::new("./a/x2"));
+        assert_eq!(resolve_resume(Path::new("./src/a"), Path::new("src")).unwrap(), Path::new("src/a"));
+        assert!(resolve_resume(Path::new("/elsewhere/a"), Path::new(root)).is_err());
+        assert!(resolve_resume(Path::new("../a"), Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn editor_format_appends_line_and_column() {
+        let dir = fixture(&["src/main.rs", "main.c"]);
+        let root = dir.path().to_str().unwrap();
+        for flags in [&["--format", "editor"][..], &["--editor-format"]] {
+            let printed = output(&config(&[&["main", root][..], flags].concat()));
+            assert_eq!(printed.lines().count(), 2, "{}", printed);
+            for line in printed.lines() {
+                let path = line.strip_suffix(":1:1").unwrap_or_else(|| panic!("{}", line));
+                assert!(Path::new(path).is_file(), "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn nfc_query_matches_nfd_name() {
+        let dir = fixture(&["cafe\u{301}.txt"]);
+        let root = dir.path().to_str().unwrap();
+        assert_eq!(found(&config(&["caf\u{e9}", root])), ["cafe\u{301}.txt"]);
+        // Both forms score the same once normalized, and as near-exact
+        let nfc = config(&["caf\u{e9}"]);
+        assert_eq!(name_score("cafe\u{301}.txt", &nfc), name_score("caf\u{e9}.txt", &nfc));
+        assert!(name_score("cafe\u{301}.txt", &nfc) > PREFIX_BOOST);
+        assert!(name_score("cafe\u{301}.txt", &config(&["caf\u{e9}", "--normalize", "none"])) < PREFIX_BOOST);
+        assert!(name_score("caf\u{e9}.txt", &config(&["cafe\u{301}", "--normalize", "nfd"])) > PREFIX_BOOST);
+    }
+
+    #[test]
+    fn show_counts_reports_the_total_past_num() {
+        let dir = many_mains(12);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "-n", "3", "--show-counts"]));
+        assert!(printed.contains("Showing 3 of 12 matches"), "{}", printed);
+        let printed = output(&config(&["main", root, "-n", "20", "--show-counts"]));
+        assert!(printed.contains("Showing 12 of 12 matches"), "{}", printed);
+        // The total survives --limit-memory keeping only the top few
+        let printed = output(&config(&["main", root, "-n", "3", "--show-counts", "--limit-memory", "0"]));
+        assert!(printed.contains("Showing 3 of 12 matches"), "{}", printed);
+    }
+
+    #[test]
+    fn list_filters_reflects_the_flags() {
+        let filters = |args: &[&str]| {
+            let mut out = Vec::new();
+            write_filters(&mut out, &config(args)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let printed = filters(&["main", "src", "--files-only", "--no-ext", "--max-gap", "2", "-s", "--allow-mount", "/a", "--allow-mount", "/b"]);
+        for line in ["query:       main", "root:        src", "types:       files", "extension:   none", "max gap:     2", "case:        Sensitive"] {
+            assert!(printed.lines().any(|l| l == line), "{:?} in\n{}", line, printed);
+        }
+        assert_eq!(printed.lines().filter(|l| l.starts_with("allow:")).count(), 2, "{}", printed);
+        // Every value starts in the same column
+        let column = |line: &str| line.find(": ").unwrap() + line[line.find(": ").unwrap() + 1..].find(|c| c != ' ').unwrap();
+        assert!(printed.lines().all(|l| column(l) == column("query:       main")), "{}", printed);
+        assert!(filters(&["main", "--dirs-only", "--files-only"]).contains("types:       none"));
+    }
+
+    #[test]
+    fn score_debug_lists_every_component() {
+        let dir = fixture(&["Main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--score-debug", "--cap-score", "100"]));
+        let debug = printed.lines().find(|l| l.contains("alignment=")).unwrap_or_else(|| panic!("{}", printed));
+        for field in ["alignment=", "exact_boost=0", "near_exact_boost=7500", "prefix_boost=0", "cap=-", "total=100"] {
+            assert!(debug.contains(field), "{} in {}", field, debug);
+        }
+        assert!(printed.contains("query=\"main\" candidate=\"main.rs\""), "{}", printed);
+        // Path boosts count too, so the total is the score in brackets
+        let config = config(&["main", root, "--score-debug", "--boost-path", "Main:300", "--theme", "matched-only"]);
+        let score = name_score("Main.rs", &config) + 300;
+        let printed = output(&config);
+        assert!(printed.contains(&format!("[{:>5}] FILE", score)), "{}", printed);
+        assert!(printed.contains(&format!(" path_boost=300 total={}\n", score)), "{}", printed);
+    }
+
+    #[test]
+    fn entry_count_filters_pick_directories_by_size() {
+        let dir = fixture(&["d1/x", "d3/x", "d3/y", "d3/z", "d"]);
+        fs::create_dir(dir.path().join("d0")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let sorted = |args: &[&str]| {
+            let mut names = found(&config(&[&["d", root][..], args].concat()));
+            names.sort();
+            names
+        };
+        assert_eq!(sorted(&["--min-entries", "1"]), ["d1", "d3"]);
+        assert_eq!(sorted(&["--max-entries", "1"]), ["d0", "d1"]);
+        assert_eq!(sorted(&["--min-entries", "1", "--max-entries", "2"]), ["d1"]);
+        assert_eq!(sorted(&["--min-entries", "4"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn query_transform_matches_across_naming_conventions() {
+        let plain = config(&["MyClass"]);
+        for transform in ["snake", "camel", "kebab"] {
+            let config = config(&["MyClass", "--query-transform", transform]);
+            for name in ["my_class.rs", "my-class.rs", "myClass.rs"] {
+                assert!(name_score(name, &config) > PREFIX_BOOST, "{} {}", transform, name);
+            }
+            assert!(name_score("my_class.rs", &config) > name_score("my_class.rs", &plain));
+        }
+        assert!(name_score("my_class.rs", &plain) < PREFIX_BOOST);
+    }
+
+    #[test]
+    fn max_gap_rejects_scattered_matches() {
+        let gap = |n: &str| name_score("axxxxxxxxxb", &config(&["ab", "--max-gap", n]));
+        assert_eq!(gap("8"), 0);
+        assert!(gap("9") > 0);
+        assert!(gap("100") > 0);
+        assert_eq!(name_score("ba", &config(&["ab", "--max-gap", "100"])), 0);
+        assert!(fits_max_gap("ab", "ab", 0));
+        assert!(fits_max_gap("ab", "axxxxxxxxxbab", 0));
+    }
+
+    #[test]
+    fn path_format_prints_just_the_paths() {
+        let dir = fixture(&["main.rs", "src/mainframe", "my_ain.c"]);
+        let root = dir.path().to_str().unwrap();
+        let paths = |n: &str| output(&config(&["main", root, "--format", "path", "-n", n]));
+        let expected: Vec<String> = ["main.rs", "src/mainframe", "my_ain.c"].iter()
+            .map(|name| format!("{}\n", dir.path().join(name).display()))
+            .collect();
+        assert_eq!(paths("10"), expected.concat());
+        assert_eq!(paths("2"), expected[..2].concat());
+    }
+
+    #[test]
+    fn scope_dir_searches_only_matching_subtrees() {
+        let dir = fixture(&["tests/config.toml", "tests/deep/config", "my_tests/config.rs", "service/config.yml", "config"]);
+        let root = dir.path().to_str().unwrap();
+        let mut names = found(&config(&["config", root, "--scope-dir", "test"]));
+        names.sort();
+        assert_eq!(names, ["my_tests/config.rs", "tests/config.toml", "tests/deep/config"]);
+        let everywhere = found(&config(&["config", root]));
+        assert!(everywhere.contains(&"service/config.yml".to_string()) && everywhere.contains(&"config".to_string()));
+    }
+
+    #[test]
+    fn fs_case_probe_flips_a_name() {
+        let names = |list: &[&str]| list.iter().map(|n| n.to_string()).collect::<HashSet<_>>();
+        let asked = std::cell::RefCell::new(Vec::new());
+        let exists = |flipped: &str| {
+            asked.borrow_mut().push(flipped.to_string());
+            true
+        };
+        assert_eq!(resolves_flipped(&names(&["ReadMe.md"]), exists), Some(true));
+        assert_eq!(asked.borrow().as_slice(), ["rEADmE.MD"]);
+        assert_eq!(resolves_flipped(&names(&["ReadMe.md"]), |_| false), Some(false));
+        // Nothing to flip, or both forms really exist
+        assert_eq!(resolves_flipped(&names(&["123", "a", "A"]), |_| true), None);
+        // A case-sensitive tmp dir on Linux; -s and -i win over the probe
+        if cfg!(target_os = "linux") {
+            let dir = fixture(&["Main.rs"]);
+            let root = dir.path().to_str().unwrap();
+            assert_eq!(config(&["main", root, "--fs-case"]).case, CaseSensitivity::Sensitive);
+            assert_eq!(config(&["main", root, "--fs-case", "-i"]).case, CaseSensitivity::Insensitive);
+        }
+    }
+
+    // Every path in the order the walk found it
+    fn discovered(config: &Config) -> Vec<PathBuf> {
+        let mut walk = Walk::new();
+        walk_dir(Path::new(&config.root), config, &mut walk).unwrap();
+        match walk.sink {
+            Sink::Collect { candidates, .. } => candidates.into_iter().map(|c| c.path).collect(),
+            Sink::Stream { .. } => unreachable!("no --limit-memory"),
+        }
+    }
+
+    #[test]
+    fn sorted_walk_visits_entries_in_lexical_order() {
+        let dir = fixture(&["b/z", "b/a", "c", "a/y/x", "A", "a/b", "_"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["x", root, "--sorted-walk"]);
+        let order = discovered(&config);
+        assert_eq!(order, discovered(&config));
+        let names: Vec<String> = order.iter().map(|p| p.strip_prefix(root).unwrap().display().to_string()).collect();
+        // Each directory right after its children
+        assert_eq!(names, ["A", "_", "a/b", "a/y/x", "a/y", "a", "b/a", "b/z", "b", "c"]);
+    }
+
+    #[test]
+    fn above_median_keeps_the_better_half() {
+        let ranked = |scores: &[i32]| -> Vec<ScoredPath> {
+            scores.iter().enumerate().map(|(i, &score)| ScoredPath { score, path: PathBuf::from(i.to_string()), mtime: None }).collect()
+        };
+        let kept = |scores: &[i32]| {
+            let mut ranked = ranked(scores);
+            trim_below_median(&mut ranked);
+            ranked.iter().map(|sp| sp.score).collect::<Vec<_>>()
+        };
+        assert_eq!(kept(&[90, 70, 50, 30, 10]), [90, 70, 50]);
+        assert_eq!(kept(&[90, 70, 50, 30, 20, 10]), [90, 70, 50]);
+        // Ties with the median stay
+        assert_eq!(kept(&[90, 50, 50, 50, 10]), [90, 50, 50, 50]);
+        assert_eq!(kept(&[]), Vec::<i32>::new());
+        // On a real search about half of a spread of scores survives
+        let dir = fixture(&["main", "main.rs", "mainframe", "x_main", "maxin", "mxaxixn", "man", "mn", "m"]);
+        let root = dir.path().to_str().unwrap();
+        let all = found(&config(&["main", root])).len();
+        let kept = found(&config(&["main", root, "--above-median"])).len();
+        assert!(kept >= all / 2 && kept < all, "{} of {}", kept, all);
+    }
+
+    #[test]
+    fn display_floor_hides_results_but_not_counts() {
+        let dir = fixture(&["main", "mainframe", "my_ain", "mxain", "maxin"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--display-min-score", &PREFIX_BOOST.to_string(), "--show-counts", "-n", "1"]);
+        let search = search(&config).unwrap();
+        assert_eq!(search.total, 5);
+        let results = select_results(&search.ranked, &config);
+        assert_eq!(results.len(), 1);
+        assert!(output(&config).contains("Showing 1 of 5 matches"));
+        // --num only counts results above the floor
+        let config = Config { num: 10, ..config };
+        assert_eq!(select_results(&search.ranked, &config).len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preview_runs_the_command_on_each_result() {
+        let dir = fixture(&["my main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let path = dir.path().join("my main.rs");
+        let printed = output(&config(&["main", root, "--theme", "matched-only", "--preview", "printf '%s\\n' {}"]));
+        assert!(printed.contains(&format!("        │ {}\n", path.display())), "{}", printed);
+        let printed = output(&config(&["main", root, "--preview", "seq 10"]));
+        assert_eq!(printed.matches('│').count(), PREVIEW_MAX_LINES + 1, "{}", printed);
+        assert!(printed.contains("│ …"));
+        let printed = output(&config(&["main", root, "--preview", "exit 3"]));
+        assert!(printed.contains("preview failed: exit status: 3"), "{}", printed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_formats_keep_non_utf8_bytes() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let dir = tempfile::tempdir().unwrap();
+        let parent = dir.path().join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        fs::create_dir(&parent).unwrap();
+        fs::write(parent.join("main"), "").unwrap();
+        for (format, end) in [("nul", b'\0'), ("path", b'\n')] {
+            let config = config(&["main", dir.path().to_str().unwrap(), "--format", format]);
+            let search = search(&config).unwrap();
+            let mut out = Vec::new();
+            write_results(&mut out, &select_results(&search.ranked, &config), search.total, None, &config).unwrap();
+            assert_eq!(out.pop(), Some(end));
+            let path = PathBuf::from(std::ffi::OsString::from_vec(out));
+            assert_eq!(path, parent.join("main"));
+            assert!(path.is_file());
+        }
+    }
+
+    #[test]
+    fn seeded_sample_is_reproducible() {
+        // Close scores, so the picks depend on the seed
+        let names: Vec<String> = (0..30).map(|i| format!("m{}ain{}", "x".repeat(1 + i % 2), i)).collect();
+        let dir = fixture(&names.iter().map(String::as_str).collect::<Vec<_>>());
+        let root = dir.path().to_str().unwrap();
+        let sample = |seed: &str| {
+            let config = config(&["main", root, "--sample", "5", "--seed", seed]);
+            let search = search(&config).unwrap();
+            select_results(&search.ranked, &config).into_iter().map(|sp| sp.path).collect::<Vec<_>>()
+        };
+        let picked = sample("42");
+        assert_eq!(picked.len(), 5);
+        assert_eq!(picked, sample("42"));
+        assert_eq!(picked.iter().collect::<HashSet<_>>().len(), 5);
+        assert!((0..5).any(|seed| sample(&seed.to_string()) != picked));
+    }
+
+    #[test]
+    fn git_directories_need_include_git() {
+        let dir = fixture(&[".git/objects/config", ".config/config", "src/config.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let mut names = found(&config(&["config", root, "--files-only"]));
+        names.sort();
+        assert_eq!(names, [".config/config", "src/config.rs"]);
+        let names = found(&config(&["config", root, "--files-only", "--include-git"]));
+        assert!(names.contains(&".git/objects/config".to_string()), "{:?}", names);
+    }
+
+    #[test]
+    fn compare_shows_both_rankings_per_result() {
+        let dir = fixture(&["main.rs", "mainframe.c", "domain.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--compare", "rs"]));
+        let lines: Vec<&str> = printed.lines().filter(|l| l.contains("FILE") || l.contains("compare:")).collect();
+        assert_eq!(lines.len(), 6, "{}", printed);
+        let other = config(&["rs"]);
+        for pair in lines.chunks(2) {
+            let name = ["main.rs", "mainframe.c", "domain.rs"].into_iter().find(|n| pair[0].ends_with(n)).unwrap();
+            match name_score(name, &other) {
+                0 => assert!(pair[1].contains("compare: no match"), "{}", pair[1]),
+                score => assert!(pair[1].contains(&format!("compare: score={} rank=", score)), "{}", pair[1]),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allow_mount_lets_one_device_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir(&share).unwrap();
+        let config = config(&["x", "-x", "--allow-mount", share.to_str().unwrap()]);
+        let share = fs::canonicalize(&share).unwrap();
+        assert!(!skips_device(1, 2, || share.clone(), &config));
+        assert!(skips_device(1, 2, || dir.path().join("other"), &config));
+        assert!(!skips_device(1, 1, || unreachable!("same device"), &config));
+        // A real mount point, where the sandbox has one
+        use std::os::unix::fs::MetadataExt;
+        let root = fs::metadata("/").unwrap();
+        if fs::metadata("/proc").is_ok_and(|proc| proc.dev() != root.dev()) {
+            assert!(crosses_mount(&root, Path::new("/proc"), &config));
+            let allowed = self::config(&["x", "-x", "--allow-mount", "/proc"]);
+            assert!(!crosses_mount(&root, Path::new("/proc"), &allowed));
+        }
+    }
+
+    #[test]
+    fn score_column_fits_the_widest_score() {
+        let dir = fixture(&["main", "my_ain", "low/main"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--theme", "matched-only", "--boost-path", "low/:-20000", "--files-only"]));
+        let rows: Vec<&str> = printed.lines().filter(|l| l.starts_with('[')).collect();
+        assert_eq!(rows.len(), 3, "{}", printed);
+        assert!(rows.iter().any(|r| r.starts_with("[-")), "{}", printed);
+        let column = rows[0].find("] FILE").unwrap();
+        assert!(rows.iter().all(|r| r.find("] FILE") == Some(column)), "{}", printed);
+        assert_eq!(column, "[-9992".len());
+    }
+
+    #[test]
+    fn structured_formats_stay_valid_without_matches() {
+        let dir = fixture(&["main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = |args: &[&str]| output(&config(&[&["qqzz", root][..], args].concat()));
+        assert_eq!(printed(&["--format", "json"]), "[]\n");
+        assert_eq!(printed(&["--format", "jsonl"]), "");
+        assert_eq!(printed(&["--format", "shell"]), "()\n");
+        for format in ["path", "nul", "editor"] {
+            assert_eq!(printed(&["--format", format]), "", "{}", format);
+        }
+        assert_eq!(printed(&["--format", "json", "--show-counts"]), "{\"total\":0,\"shown\":0,\"results\":[]}\n");
+        assert_eq!(printed(&["--format", "jsonl", "--show-counts"]), "{\"total\":0,\"shown\":0}\n");
+        assert_eq!(read_result_paths(&printed(&["--format", "json"])).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn density_ranks_tight_matches_above_spread_ones() {
+        let density = config(&["abc", "--rank", "density"]);
+        let (tight, sparse) = ("xxxxxxxxxxxxabcxxxxxxxxxxx", "axbxc");
+        assert!(name_score(tight, &density) > name_score(sparse, &density));
+        assert_eq!(name_score("abc", &density), EXACT_BOOST + DENSITY_SCALE);
+        // Length doesn't count against the tight match
+        assert_eq!(name_score(tight, &density), name_score("xabc", &density));
+    }
+
+    #[test]
+    fn limit_memory_keeps_the_same_top_results() {
+        let dir = many_mains(300);
+        let root = dir.path().to_str().unwrap();
+        let top = |args: &[&str]| {
+            let config = config(&[&["main", root, "-n", "7"][..], args].concat());
+            let search = search(&config).unwrap();
+            (select_results(&search.ranked, &config), search.ranked.len(), search.total)
+        };
+        let (collected, all, total) = top(&[]);
+        let (streamed, kept, streamed_total) = top(&["--limit-memory", "0"]);
+        assert!(streamed == collected);
+        assert_eq!((all, total, streamed_total), (300, 300, 300));
+        assert!(kept < 10, "kept {}", kept);
+    }
+
+    #[test]
+    fn json_fields_selects_and_orders_keys() {
+        let dir = fixture(&["main.rs", "src/main"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--format", "jsonl", "--json-fields", "score,path"]));
+        for line in printed.lines() {
+            assert!(line.starts_with("{\"score\":") && line.contains(",\"path\":\"") && line.ends_with("\"}"), "{}", line);
+            assert!(!line.contains("\"type\""), "{}", line);
+        }
+        let printed = output(&config(&["main", root, "--format", "json", "--json-fields", "rank"]));
+        assert_eq!(printed, "[{\"rank\":1},{\"rank\":2}]\n");
+        let error = Config::parse(["shodh", "main", "--json-fields", "path,size"].map(String::from).to_vec()).unwrap_err();
+        assert_eq!(error, "Unknown field for --json-fields: size");
+    }
+
+    #[test]
+    fn parallel_scoring_keeps_the_order() {
+        let dir = many_mains(200);
+        let root = dir.path().to_str().unwrap();
+        let order = |args: &[&str]| search(&config(&[&["main", root, "--sorted-walk"][..], args].concat())).unwrap().ranked;
+        let sequential = order(&["--no-parallel"]);
+        for _ in 0..3 {
+            assert!(order(&["--parallel-threshold", "0", "--parallel-chunk-size", "1"]) == sequential);
+        }
+    }
+
+    #[test]
+    fn matching_root_is_not_a_result() {
+        let dir = fixture(&["main/main.rs", "main/src/main"]);
+        let root = dir.path().join("main");
+        let mut names = found(&config(&["main", root.to_str().unwrap()]));
+        names.sort();
+        assert_eq!(names, ["main.rs", "src/main"]);
+    }
+
+    #[test]
+    fn component_filters_count_the_shown_path() {
+        assert_eq!(component_count(Path::new("./src/main.rs")), 2);
+        assert_eq!(component_count(Path::new("/usr/src/main.rs")), 3);
+        assert_eq!(component_count(Path::new("../main.rs")), 2);
+        let dir = fixture(&["main", "a/main", "a/b/main"]);
+        let root = dir.path().to_str().unwrap();
+        let depth = component_count(dir.path());
+        let only = |args: &[&str]| {
+            let mut names = found(&config(&[&["main", root, "--files-only"][..], args].concat()));
+            names.sort();
+            names
+        };
+        assert_eq!(only(&["--min-components", &(depth + 2).to_string()]), ["a/b/main", "a/main"]);
+        assert_eq!(only(&["--max-components", &(depth + 2).to_string()]), ["a/main", "main"]);
+        assert_eq!(only(&["--min-components", &(depth + 3).to_string(), "--max-components", &(depth + 3).to_string()]), ["a/b/main"]);
+    }
+
+    #[test]
+    fn calibration_suggests_the_floor_above_the_gap() {
+        let ranked: Vec<ScoredPath> = [5012, 5010, 5010, 5006, 14, 12, 12, 8, 6].iter().enumerate()
+            .map(|(i, &score)| ScoredPath { score, path: PathBuf::from(i.to_string()), mtime: None })
+            .collect();
+        let tiers = score_tiers(&ranked);
+        assert_eq!(tiers, [(5012, 1), (5010, 2), (5006, 1), (14, 1), (12, 2), (8, 1), (6, 1)]);
+        assert_eq!(widest_gap(&tiers), Some((5006, 14)));
+        assert_eq!(widest_gap(&score_tiers(&ranked[..1])), None);
+        // On a real search the exact and near-exact names clear the fuzzy ones
+        let dir = fixture(&["main", "Main.rs", "my_ain", "mxain", "maxin"]);
+        let search = search(&config(&["main", dir.path().to_str().unwrap(), "--calibrate"])).unwrap();
+        let (floor, _) = widest_gap(&score_tiers(&search.ranked)).unwrap();
+        assert_eq!(search.ranked.iter().filter(|sp| sp.score >= floor).count(), 2);
+    }
+
+    #[test]
+    fn mark_hidden_tags_only_dot_entries() {
+        let dir = fixture(&[".hidden/main", ".main", "src/main", "src/.cache/main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--mark-hidden", "--files-only", "--theme", "matched-only"]));
+        let rows: Vec<&str> = printed.lines().filter(|l| l.starts_with('[')).collect();
+        assert_eq!(rows.len(), 4, "{}", printed);
+        for row in rows {
+            assert_eq!(row.ends_with("  [hidden]"), !row.contains("src/\x1b[1mm"), "{}", row);
+        }
+        let json = output(&config(&["main", root, "--mark-hidden", "--files-only", "--format", "jsonl"]));
+        assert_eq!(json.matches("\"hidden\":true").count(), 3, "{}", json);
+        assert_eq!(json.matches("\"hidden\":false").count(), 1, "{}", json);
+        // The fixture root is itself a dot directory, which doesn't count
+        assert!(dir.path().file_name().unwrap().to_str().unwrap().starts_with('.'));
+    }
+
+    #[test]
+    fn score_transform_changes_only_the_column() {
+        let dir = fixture(&["main", "mainframe", "my_ain"]);
+        let root = dir.path().to_str().unwrap();
+        let rows = |transform: &str| -> Vec<(String, String)> {
+            output(&config(&["main", root, "--theme", "matched-only", "--score-transform", transform])).lines()
+                .filter_map(|l| l.strip_prefix('['))
+                .map(|l| {
+                    let (score, rest) = l.split_once(']').unwrap();
+                    (score.trim().to_string(), rest.to_string())
+                })
+                .collect()
+        };
+        let (raw, log, rank) = (rows("none"), rows("log"), rows("rank"));
+        let paths = |rows: &[(String, String)]| rows.iter().map(|(_, path)| path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(&log), paths(&raw));
+        assert_eq!(paths(&rank), paths(&raw));
+        assert_eq!(rank.iter().map(|(score, _)| score.as_str()).collect::<Vec<_>>(), ["1", "2", "3"]);
+        for ((raw, _), (log, _)) in raw.iter().zip(&log) {
+            assert_eq!(*log, format!("{:.2}", raw.parse::<f64>().unwrap().ln_1p()));
+        }
+        assert_eq!(display_score(-10000, 0, ScoreTransform::Log), "-9.21");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_need_include_special() {
+        let dir = fixture(&["main.rs"]);
+        let fifo = dir.path().join("main.fifo");
+        assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        let root = dir.path().to_str().unwrap();
+        assert_eq!(found(&config(&["main", root])), ["main.rs"]);
+        let mut names = found(&config(&["main", root, "--include-special"]));
+        names.sort();
+        assert_eq!(names, ["main.fifo", "main.rs"]);
+    }
+
+    #[test]
+    fn diff_against_classifies_each_result() {
+        let dir = fixture(&["main", "mainframe", "my_ain", "maxin"]);
+        let root = dir.path().to_str().unwrap();
+        let shown = |name: &str| dir.path().join(name).display().to_string();
+        // The previous run: the top two swapped, maxin where it is now, my_ain not yet there and one since deleted
+        let saved = tempfile::tempdir().unwrap();
+        let previous = saved.path().join("previous.jsonl");
+        let lines: Vec<String> = ["mainframe", "main", "maxin", "gone"].iter()
+            .map(|name| format!("{{\"path\":{},\"score\":1}}", json_string(&shown(name))))
+            .collect();
+        fs::write(&previous, lines.join("\n")).unwrap();
+        let printed = output(&config(&["main", root, "--format", "jsonl", "--diff-against", previous.to_str().unwrap()]));
+        let rows: Vec<&str> = printed.lines().collect();
+        assert_eq!(rows.len(), 5, "{}", printed);
+        assert!(rows[0].contains("/main\",") && rows[0].ends_with("\"change\":\"moved\",\"previous_rank\":2}"), "{}", rows[0]);
+        assert!(rows[1].contains("/mainframe\",") && rows[1].ends_with("\"change\":\"moved\",\"previous_rank\":1}"), "{}", rows[1]);
+        assert!(rows[2].contains("/maxin\",") && rows[2].ends_with("\"change\":\"same\"}"), "{}", rows[2]);
+        assert!(rows[3].contains("/my_ain\",") && rows[3].ends_with("\"change\":\"added\"}"), "{}", rows[3]);
+        assert_eq!(rows[4], format!("{{\"path\":{},\"change\":\"removed\"}}", json_string(&shown("gone"))));
+        // Diffing against that diff: what it marked removed wasn't one of its results
+        let diffed = saved.path().join("diffed.jsonl");
+        fs::write(&diffed, &printed).unwrap();
+        fs::write(dir.path().join("gone"), "").unwrap();
+        let printed = output(&config(&["main", root, "--format", "jsonl", "--diff-against", diffed.to_str().unwrap()]));
+        let rows: Vec<&str> = printed.lines().collect();
+        assert_eq!(rows.len(), 5, "{}", printed);
+        let gone = rows.iter().find(|row| row.contains("/gone\",")).unwrap();
+        assert!(gone.ends_with("\"change\":\"added\"}"), "{}", gone);
+        assert!(rows.iter().filter(|row| *row != gone).all(|row| row.ends_with("\"change\":\"same\"}")), "{}", printed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_listed_but_not_entered() {
+        let dir = fixture(&["real/main.rs"]);
+        std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("main_dir")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let names = found(&config(&["main", root]));
+        assert!(names.contains(&"main_dir".to_string()) && names.contains(&"real/main.rs".to_string()), "{:?}", names);
+        assert!(!names.contains(&"main_dir/main.rs".to_string()), "{:?}", names);
+        let names = found(&config(&["main", root, "--follow-symlinks"]));
+        assert!(names.contains(&"main_dir".to_string()) && names.contains(&"main_dir/main.rs".to_string()), "{:?}", names);
+    }
+
+    #[test]
+    fn match_report_sums_up_a_known_fixture() {
+        let dir = fixture(&["main", "Main.rs", "mainframe", "xain", "ma_in"]);
+        let config = config(&["main", dir.path().to_str().unwrap(), "--match-report"]);
+        let report = MatchReport::new(&search(&config).unwrap().ranked, &config);
+        assert_eq!((report.matches, report.exact, report.near_exact, report.prefix, report.fuzzy), (5, 1, 1, 1, 2));
+        // xain matches 3 of 4 chars; ma_in all 4, one char missing over 3 steps
+        assert!((report.coverage - 4.75 / 5.0).abs() < 1e-9, "{}", report.coverage);
+        assert!((report.gap - 1.0 / 3.0 / 5.0).abs() < 1e-9, "{}", report.gap);
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(),
+            "{\"matches\":5,\"coverage\":0.9500,\"gap\":0.0667,\"exact\":0.2000,\"near_exact\":0.2000,\"prefix\":0.2000,\"fuzzy\":0.4000}\n");
+    }
+
+    #[test]
+    fn extension_filters_skip_directories() {
+        let dir = fixture(&["Makefile", "README", "main.rs", "data.d/x", ".bashrc"]);
+        let root = dir.path().to_str().unwrap();
+        let sorted = |flag: &str| {
+            let mut names = found(&config(&["a", root, flag]));
+            names.sort();
+            names
+        };
+        // Dotfiles count as extensionless
+        assert_eq!(sorted("--no-ext"), [".bashrc", "Makefile", "README", "data.d"]);
+        assert_eq!(sorted("--require-ext"), ["data.d", "main.rs"]);
+    }
+
+    #[test]
+    fn mtime_tiebreak_puts_the_newer_tie_first() {
+        let dir = fixture(&["a/main", "b/main", "mainframe"]);
+        let root = dir.path().to_str().unwrap();
+        let touch = |name: &str, secs: u64| {
+            let file = fs::File::options().write(true).open(dir.path().join(name)).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+        touch("a/main", 1_000_000);
+        touch("b/main", 2_000_000);
+        touch("mainframe", 3_000_000);
+        assert_eq!(found(&config(&["main", root, "--files-only"])), ["a/main", "b/main", "mainframe"]);
+        // Only ties reorder: the newest file still ranks below the exact names
+        assert_eq!(found(&config(&["main", root, "--files-only", "--tiebreak", "mtime"])), ["b/main", "a/main", "mainframe"]);
+    }
+
+    #[test]
+    fn verbatim_skips_tidying_and_normalization() {
+        let tidied = config(&["  my   file  "]);
+        assert_eq!(tidied.query, "my file");
+        let verbatim = config(&["  my   file  ", "--verbatim"]);
+        assert_eq!(verbatim.query, "  my   file  ");
+        assert_eq!(verbatim.case, CaseSensitivity::Sensitive);
+        // NFD name against an NFC query: only equal once normalized
+        let nfd = "cafe\u{301}";
+        assert!(name_score(nfd, &config(&["caf\u{e9}"])) > PREFIX_BOOST);
+        assert!(name_score(nfd, &config(&["caf\u{e9}", "--verbatim"])) < PREFIX_BOOST);
+        assert!(name_score("my_class", &config(&["MyClass", "--query-transform", "snake", "--verbatim", "-i"])) < PREFIX_BOOST);
+        assert_eq!(config(&["Main", "--verbatim", "-i"]).case, CaseSensitivity::Insensitive);
+    }
+
+    #[test]
+    fn show_rank_numbers_the_shown_results_from_one() {
+        let dir = many_mains(12);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--show-rank", "-n", "10", "--exact-first", "--theme", "matched-only"]));
+        let ranks: Vec<&str> = printed.lines().filter_map(|l| l.split_once(". [").map(|(rank, _)| rank.trim())).collect();
+        assert_eq!(ranks, (1..=10).map(|n| n.to_string()).collect::<Vec<_>>(), "{}", printed);
+        // Padded to the widest rank
+        assert!(printed.contains("\n 1. [") && printed.contains("\n10. ["), "{}", printed);
+        let json = output(&config(&["main", root, "--show-rank", "-n", "3", "--format", "jsonl"]));
+        let ranks: Vec<&str> = json.lines().map(|l| &l[..10]).collect();
+        assert_eq!(ranks, ["{\"rank\":1,", "{\"rank\":2,", "{\"rank\":3,"]);
+    }
+
+    #[test]
+    fn stray_query_whitespace_is_collapsed() {
+        let padded = config(&["  conf   toml  "]);
+        assert_eq!(padded.query, "conf toml");
+        assert_eq!(name_score("conf.toml", &padded), name_score("conf.toml", &config(&["conf toml"])));
+        assert!(name_score("conf.toml", &padded) > name_score("settings.toml", &padded));
+        let empty = Config::parse(["shodh", "  \t "].map(String::from).to_vec()).unwrap_err();
+        assert_eq!(empty, "Query is empty. Use -h for help.");
+        assert_eq!(config(&["main", "--compare", " a  b "]).compare.as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn shell_format_quotes_each_path() {
+        assert_eq!(shell_quote("a b.txt"), "'a b.txt'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        let dir = fixture(&["my file's main.rs", "main"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--format", "shell"]));
+        let quoted = |name: &str| shell_quote(&dir.path().join(name).display().to_string());
+        assert_eq!(printed, format!("({} {})\n", quoted("main"), quoted("my file's main.rs")));
+        // What bash makes of it, where there is one
+        let script = format!("eval \"files={}\"; printf '%s\\n' \"${{files[@]}}\"", printed.trim_end());
+        if let Ok(shell) = Command::new("bash").args(["-c", &script]).output() {
+            assert_eq!(String::from_utf8(shell.stdout).unwrap(),
+                format!("{}\n{}\n", dir.path().join("main").display(), dir.path().join("my file's main.rs").display()));
+        }
+    }
+
+    #[test]
+    fn cap_lets_later_adjustments_reorder_top_matches() {
+        let dir = fixture(&["main", "src/mainframe"]);
+        let root = dir.path().to_str().unwrap();
+        let boosted = ["--boost-path", "src/:100", "--files-only"];
+        assert_eq!(found(&config(&[&["main", root][..], &boosted].concat())), ["main", "src/mainframe"]);
+        assert_eq!(found(&config(&[&["main", root, "--cap-score", "5000"][..], &boosted].concat())), ["src/mainframe", "main"]);
+        // Capped scores tie, so the newer file wins under --tiebreak mtime
+        let file = fs::File::options().write(true).open(dir.path().join("main")).unwrap();
+        file.set_modified(UNIX_EPOCH).unwrap();
+        let newest = ["--tiebreak", "mtime", "--files-only"];
+        assert_eq!(found(&config(&[&["main", root][..], &newest].concat())), ["main", "src/mainframe"]);
+        assert_eq!(found(&config(&[&["main", root, "--cap-score", "5000"][..], &newest].concat())), ["src/mainframe", "main"]);
+    }
+
+    #[test]
+    fn directories_get_a_trailing_slash() {
+        let dir = fixture(&["main/x", "main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = output(&config(&["main", root, "--format", "path"]));
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(printed, format!("{}{}\n{}\n", dir.path().join("main").display(), sep, dir.path().join("main.rs").display()));
+        let printed = output(&config(&["main", root, "--format", "path", "--no-slash"]));
+        assert_eq!(printed, format!("{}\n{}\n", dir.path().join("main").display(), dir.path().join("main.rs").display()));
+        let printed = output(&config(&["main", root]));
+        assert!(printed.contains(&format!("  {}{}\n", dir.path().join("main").display(), sep)), "{}", printed);
+        assert!(printed.contains(&format!("  {}\n", dir.path().join("main.rs").display())), "{}", printed);
+        // JSON has the type field instead
+        assert!(!output(&config(&["main", root, "--format", "json"])).contains(&format!("main{}\"", sep)));
+    }
+
+    #[test]
+    fn files_never_match_through_their_directory() {
+        let dir = fixture(&["main/todo.txt", "src/main/x", "tools/main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let mut names = found(&config(&["main", root, "--files-only"]));
+        names.sort();
+        assert_eq!(names, ["tools/main.rs"]);
+    }
+
+    #[test]
+    fn simple_color_keeps_only_basic_codes() {
+        let dir = fixture(&["main/x", "main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--simple-color", "--show-counts", "--score-debug"]);
+        let search = search(&config).unwrap();
+        let mut out = Vec::new();
+        write_results(&mut SimpleColor(&mut out), &select_results(&search.ranked, &config), search.total, None, &config).unwrap();
+        let printed = String::from_utf8(out).unwrap();
+        assert!(printed.contains("\x1b[34m") && printed.contains("\x1b[33m"), "{:?}", printed);
+        for part in printed.split("\x1b[").skip(1) {
+            let params = &part[..part.find('m').unwrap()];
+            assert!(params.split(';').all(|p| p == "0" || (p.len() == 2 && (p.starts_with('3') || p.starts_with('4')))), "{:?}", params);
+        }
+        // Bold-only and dim-only escapes vanish whole
+        let mut out = Vec::new();
+        SimpleColor(&mut out).write_all(b"\x1b[1;31mError:\x1b[0m \x1b[2mdim\x1b[0m \x1b[1mbold\x1b[0m").unwrap();
+        assert_eq!(out, b"\x1b[31mError:\x1b[0m dim\x1b[0m bold\x1b[0m");
+    }
+
+    #[test]
+    fn not_exact_drops_only_the_exact_name() {
+        let dir = fixture(&["Main", "src/main", "main.rs", "mainframe", "my_ain"]);
+        let root = dir.path().to_str().unwrap();
+        let mut names = found(&config(&["main", root, "--not-exact"]));
+        names.sort();
+        assert_eq!(names, ["main.rs", "mainframe", "my_ain"]);
+        // Case-sensitive, only the name equal in case is exact
+        let mut names = found(&config(&["main", root, "--not-exact", "-s", "--files-only"]));
+        names.sort();
+        assert_eq!(names, ["Main", "main.rs", "mainframe", "my_ain"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedupe_hardlinks_scores_one_name_per_file() {
+        let dir = fixture(&["a/main.rs", "other.rs"]);
+        fs::create_dir(dir.path().join("b")).unwrap();
+        fs::hard_link(dir.path().join("a/main.rs"), dir.path().join("b/main.rs")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let files = |args: &[&str]| found(&config(&[&["main.rs", root, "--files-only", "--sorted-walk"][..], args].concat()));
+        assert_eq!(files(&[]).len(), 3);
+        // The first name in walk order wins
+        let names = files(&["--dedupe-hardlinks"]);
+        assert!(names.contains(&"a/main.rs".to_string()) && !names.contains(&"b/main.rs".to_string()), "{:?}", names);
+        assert_eq!(names.len(), 2);
+    }
+
+    #[test]
+    fn exact_first_fills_with_fuzzy_after_the_anchored_tier() {
+        let dir = fixture(&["main", "mainframe", "boosted/my_ain", "mxain", "maxin"]);
+        let root = dir.path().to_str().unwrap();
+        let args = ["main", root, "--files-only", "--boost-path", "boosted/:9000", "-n", "4"];
+        let config_plain = config(&args);
+        let plain = select_results(&search(&config_plain).unwrap().ranked, &config_plain);
+        assert!(plain[1].path.ends_with("boosted/my_ain"));
+        let config = config(&[&args[..], &["--exact-first"]].concat());
+        let results = select_results(&search(&config).unwrap().ranked, &config);
+        let names: Vec<&str> = results.iter().map(|sp| sp.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["main", "mainframe", "my_ain", "maxin"]);
+        let printed = output(&config);
+        let divider = printed.find("fuzzy matches:").unwrap();
+        assert!(printed.find("mainframe").unwrap() < divider && divider < printed.find("my_ain").unwrap(), "{}", printed);
+    }
+
+    #[test]
+    fn suffix_keeps_only_names_ending_with_the_query() {
+        let dir = fixture(&["parse_test.rs", "Lex_Test.rs", "parse.rs", "test_util.rs", "contest.rsx"]);
+        let mut names = found(&config(&["_test.rs", dir.path().to_str().unwrap(), "--suffix"]));
+        names.sort();
+        assert_eq!(names, ["Lex_Test.rs", "parse_test.rs"]);
+    }
+
+    #[test]
+    fn line_counts_count_a_last_line_without_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in [("empty.rs", ""), ("one.rs", "fn main() {}"), ("three.rs", "a\nb\nc\n"), ("blob.rs", "a\n\0b\n")] {
+            fs::write(dir.path().join(name), text).unwrap();
+        }
+        let counts: Vec<Option<usize>> = ["empty.rs", "one.rs", "three.rs", "blob.rs"].iter()
+            .map(|name| count_lines(&dir.path().join(name)))
+            .collect();
+        assert_eq!(counts, [Some(0), Some(1), Some(3), None]);
+        let printed = unhighlighted(&config(&["three", dir.path().to_str().unwrap(), "--line-counts", "--theme", "matched-only"]));
+        assert!(printed.lines().any(|line| line.contains("three.rs") && line.ends_with("[3 lines]")), "{}", printed);
+    }
+
+    #[test]
+    fn collapse_prefix_prints_the_shared_directory_once() {
+        let dir = fixture(&["deep/er/still/main.rs", "deep/er/still/x/main.c", "deep/er/still/y/z/main.h"]);
+        let root = dir.path().to_str().unwrap();
+        let printed = unhighlighted(&config(&["main", root, "--files-only", "--collapse-prefix", "--theme", "matched-only"]));
+        let shared = dir.path().join("deep/er/still");
+        assert_eq!(printed.matches(shared.to_str().unwrap()).count(), 1, "{}", printed);
+        assert!(printed.contains(&format!("{}/\n", shared.display())), "{}", printed);
+        for shown in ["main.rs", "x/main.c", "y/z/main.h"] {
+            assert!(printed.lines().any(|line| line.trim_end().ends_with(&format!(" {}", shown))), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn a_lone_match_is_scored_like_any_other() {
+        let dir = fixture(&["main", "main.rs", "xyz.txt"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--require-ext", "--files-only", "--format", "jsonl"]);
+        let search = search(&config).unwrap();
+        assert_eq!(search.ranked.len(), 1);
+        assert_eq!(search.ranked[0].path, dir.path().join("main.rs"));
+        assert_eq!(search.ranked[0].score, name_score("main.rs", &config));
+        let printed = output(&config);
+        assert_eq!(printed.lines().count(), 1, "{}", printed);
+        assert!(printed.contains(&format!("\"score\":{}", name_score("main.rs", &config))), "{}", printed);
+    }
+
+    #[test]
+    fn quote_wraps_only_the_path_in_shell_quotes() {
+        let dir = fixture(&["dir x/it's main.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let config = config(&["main", root, "--files-only", "--quote"]);
+        let score = name_score("it's main.rs", &config);
+        let quoted = format!("'{}/dir x/it'\\''s main.rs'", root);
+        let printed = output(&config);
+        let line = printed.lines().find(|line| line.contains("main.rs")).unwrap();
+        assert_eq!(line, format!("\x1b[1;33m[{:>5}] FILE\x1b[1;33m\x1b[0m  {}", score, quoted));
+        let printed = output(&Config { theme: Theme::MatchedOnly, ..config });
+        assert!(printed.contains(&format!("\n[{:>5}] FILE  {}\n", score, quoted)), "{}", printed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn match_target_name_scores_links_by_where_they_point() {
+        let targets = fixture(&["main_config.rs"]);
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(targets.path().join("main_config.rs"), dir.path().join("zz")).unwrap();
+        std::os::unix::fs::symlink(targets.path().join("gone/main.rs"), dir.path().join("qq")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        assert!(found(&config(&["main", root])).is_empty());
+        let config = config(&["main", root, "--match-target-name", "--theme", "matched-only"]);
+        let mut names = found(&config);
+        names.sort();
+        assert_eq!(names, ["qq", "zz"]);
+        let printed = unhighlighted(&config);
+        assert!(printed.contains(&format!("zz -> {}", targets.path().join("main_config.rs").display())), "{}", printed);
+        assert!(printed.contains(&format!("qq -> {}", targets.path().join("gone/main.rs").display())), "{}", printed);
+        // Everything that looks at the name again sees the target's
+        let zz = dir.path().join("zz");
+        let score = search(&config).unwrap().ranked.iter().find(|sp| sp.path == zz).unwrap().score;
+        let mut debug = Vec::new();
+        write_score_debug(&mut debug, &zz, &config).unwrap();
+        let debug = String::from_utf8(debug).unwrap();
+        assert!(debug.contains("candidate=\"main_config.rs\"") && debug.contains(&format!(" total={}\n", score)), "{}", debug);
+        assert!(is_anchored(&zz, &config));
+        assert_eq!(score_components(&zz, &config).unwrap().iter().map(|(_, n)| n).sum::<i32>(), score);
+        let printed = output(&config);
+        assert!(printed.contains("\x1b[1mm\x1b[0m\x1b[1ma\x1b[0m\x1b[1mi\x1b[0m\x1b[1mn\x1b[0m_config.rs"), "{}", printed);
+        let report = MatchReport::new(&search(&config).unwrap().ranked, &config);
+        assert_eq!((report.near_exact, report.prefix, report.fuzzy), (1, 1, 0));
+    }
+
+    #[test]
+    fn boost_path_lifts_src_over_an_identical_test_match() {
+        let dir = fixture(&["src/parse.rs", "test/parse.rs"]);
+        let root = dir.path().to_str().unwrap();
+        let plain = found(&config(&["parse.rs", root, "--files-only"]));
+        assert_eq!(plain, ["src/parse.rs", "test/parse.rs"]);
+        let boosted = config(&["parse.rs", root, "--files-only", "--boost-path", "/test/:200", "--boost-path", "/src/:-100"]);
+        assert_eq!(found(&boosted), ["test/parse.rs", "src/parse.rs"]);
+        let demoted = config(&["parse.rs", root, "--files-only", "--boost-path", "/test/:-50"]);
+        let ranked = search(&demoted).unwrap().ranked;
+        assert_eq!(ranked[0].score - ranked[1].score, 50);
+        assert!(ranked[0].path.ends_with("src/parse.rs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owner_keeps_files_of_the_given_uid() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = fixture(&["main.rs", "mainframe"]);
+        let root = dir.path().to_str().unwrap();
+        let uid = fs::metadata(dir.path()).unwrap().uid();
+        let mine = found(&config(&["main", root, "--owner", &uid.to_string()]));
+        assert_eq!(mine, ["main.rs", "mainframe"]);
+        assert!(found(&config(&["main", root, "--owner", &(uid + 1).to_string()])).is_empty());
+        // Only root can hand a file to someone else
+        if uid == 0 {
+            std::os::unix::fs::chown(dir.path().join("mainframe"), Some(65534), None).unwrap();
+            assert_eq!(found(&config(&["main", root, "--owner", "0"])), ["main.rs"]);
+            assert_eq!(found(&config(&["main", root, "--owner", "65534"])), ["mainframe"]);
+        }
+    }
+
+    #[test]
+    fn prune_stops_at_directories_matching_from_the_start() {
+        let dir = fixture(&["x/target/target.d", "lib/targets/target.d", "zzz/untarget/target.d"]);
+        let root = dir.path().to_str().unwrap();
+        let mut names = found(&config(&["target", root, "--prune"]));
+        names.sort();
+        assert_eq!(names, ["lib/targets", "x/target", "zzz/untarget", "zzz/untarget/target.d"]);
+        let walked = discovered(&config(&["target", root, "--prune"]));
+        assert!(!walked.iter().any(|p| p.ends_with("target/target.d") || p.ends_with("targets/target.d")), "{:?}", walked);
+        assert_eq!(found(&config(&["target", root])).len(), 6);
+    }
+
+    // Made-up candidates for the timing tests; scoring never touches the disk
+    fn bench_candidates(n: usize) -> Vec<Candidate> {
+        (0..n).map(|i| Candidate { path: PathBuf::from(format!("/bench/d{}/src_{}_module_name.rs", i % 97, i)), entries: None }).collect()
+    }
+
+    // Best of a few runs, to keep one slow run from skewing the comparison
+    fn best_of(runs: usize, mut f: impl FnMut()) -> Duration {
+        (0..runs).map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        }).min().unwrap()
+    }
+
+    // Sequential vs parallel scoring at sizes around DEFAULT_PARALLEL_THRESHOLD.
+    // The walk is sequential either way; only `rank` is timed.
+    #[test]
+    #[ignore = "timing; run with cargo test --release -- --ignored --nocapture"]
+    fn bench_parallel_threshold() {
+        let parallel = config(&["modname", ".", "--parallel-threshold", "0"]);
+        let sequential = Config { parallel: false, ..config(&["modname", "."]) };
+        println!("{} threads", rayon::current_num_threads());
+        for n in [256, 512, 1024, 2048, 4096, 16384, 65536] {
+            let candidates = bench_candidates(n);
+            let seq = best_of(5, || { rank(&candidates, &sequential.query, &sequential); });
+            let par = best_of(5, || { rank(&candidates, &parallel.query, &parallel); });
+            println!("{:>6} candidates: sequential {:>9.3?}  parallel {:>9.3?}", n, seq, par);
+        }
+    }
+
+    // Parallel scoring of one large set at fixed chunk sizes and at the auto
+    // default of PARALLEL_TASKS_PER_THREAD tasks per thread
+    #[test]
+    #[ignore = "timing; run with cargo test --release -- --ignored --nocapture"]
+    fn bench_parallel_chunk_size() {
+        let candidates = bench_candidates(100_000);
+        let threads = rayon::current_num_threads();
+        println!("{} threads, {} candidates", threads, candidates.len());
+        let auto = candidates.len() / (threads * PARALLEL_TASKS_PER_THREAD);
+        for chunk in [Some(1), Some(64), Some(1024), Some(16384), None] {
+            let mut args = vec!["modname", ".", "--parallel-threshold", "0"];
+            let size = chunk.map(|n: usize| n.to_string());
+            if let Some(size) = &size {
+                args.extend(["--parallel-chunk-size", size]);
+            }
+            let config = config(&args);
+            let took = best_of(3, || { rank(&candidates, &config.query, &config); });
+            let label = size.unwrap_or_else(|| format!("auto ({})", auto));
+            println!("chunk {:>12}: {:>9.3?}", label, took);
+        }
+    }
+
+    // Records each write that reaches it, to see what buffering lets through
+    struct Writes(std::rc::Rc<std::cell::RefCell<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn line_buffered_passes_each_line_on_as_it_ends() {
+        let dir = many_mains(5);
+        let through = |extra: &[&str]| {
+            let config = config(&[&["main", dir.path().to_str().unwrap(), "--format", "path"][..], extra].concat());
+            let writes = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            let mut out = buffer_output(Writes(writes.clone()), &config);
+            let search = search(&config).unwrap();
+            write_results(&mut *out, &search.ranked, search.total, None, &config).unwrap();
+            drop(out);
+            writes.take()
+        };
+        // One write per line, so a live reader sees each result as it comes
+        let line = through(&["--line-buffered"]);
+        assert_eq!(line.len(), 5, "{:?}", line);
+        assert!(line.iter().all(|w| w.ends_with(b"\n") && w.iter().filter(|&&b| b == b'\n').count() == 1));
+        // One write for the lot
+        let block = through(&[]);
+        assert_eq!(block.len(), 1);
+        assert_eq!(line.concat(), block.concat());
+    }
+
+    // Writing many result lines to a file unbuffered, line-buffered and
+    // block-buffered, the three ways stdout could be used
+    #[test]
+    #[ignore = "timing; run with cargo test --release -- --ignored --nocapture"]
+    fn bench_output_buffering() {
+        let paths: Vec<PathBuf> = bench_candidates(200_000).into_iter().map(|c| c.path).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let file = || fs::File::create(dir.path().join("out")).unwrap();
+        let write_lines = |out: &mut dyn Write| {
+            for path in &paths {
+                writeln!(out, "{}", path.display()).unwrap();
+            }
+            out.flush().unwrap();
+        };
+        let unbuffered = best_of(3, || write_lines(&mut file()));
+        let line = best_of(3, || write_lines(&mut *buffer_output(file(), &config(&["x", ".", "--line-buffered"]))));
+        let block = best_of(3, || write_lines(&mut *buffer_output(file(), &config(&["x", "."]))));
+        println!("{} lines: unbuffered {:.3?}  line-buffered {:.3?}  block-buffered {:.3?}", paths.len(), unbuffered, line, block);
+    }
+
+    proptest! {
+        #[test]
+        fn scores_are_non_negative(q in "[a-zA-Z0-9_.]{0,12}", c in "[a-zA-Z0-9_.]{0,24}", ranking in rankings()) {
+            prop_assert!(fuzzy_score(&q, &c, scoring(ranking)) >= 0);
+        }
+
+        #[test]
+        fn exact_match_outscores_any_other(q in "[a-zA-Z0-9_.]{1,12}", c in "[a-zA-Z0-9_.]{1,24}", ranking in rankings()) {
+            prop_assume!(q != c);
+            let scoring = scoring(ranking);
+            prop_assert!(fuzzy_score(&q, &q, scoring) > fuzzy_score(&q, &c, scoring));
+        }
+
+        #[test]
+        fn prefix_match_outscores_fuzzy_match(c in "[a-z0-9_.]{2,24}", k in 1usize..24, q in "[a-z0-9_.]{1,12}", ranking in rankings()) {
+            let prefix = &c[..k.min(c.len() - 1)];
+            prop_assume!(!c.starts_with(q.as_str()) && !is_near_exact(&q, &c));
+            let scoring = scoring(ranking);
+            prop_assert!(fuzzy_score(prefix, &c, scoring) > fuzzy_score(&q, &c, scoring));
+        }
+
+        #[test]
+        fn matching_char_never_lowers_alignment(q in "[a-z]{0,12}", c in "[a-z]{1,24}", pick in any::<prop::sample::Index>()) {
+            let ch = c.as_bytes()[pick.index(c.len())] as char;
+            let longer = format!("{}{}", q, ch);
+            let scoring = scoring(Ranking::Alignment);
+            prop_assert!(fuzzy_breakdown(&longer, &c, scoring).alignment >= fuzzy_breakdown(&q, &c, scoring).alignment);
+        }
+
+        #[test]
+        fn extending_a_prefix_never_lowers_the_score(c in "[a-z0-9_]{1,24}", k in 0usize..24) {
+            let k = k.min(c.len() - 1);
+            let scoring = scoring(Ranking::Alignment);
+            prop_assert!(fuzzy_score(&c[..k + 1], &c, scoring) >= fuzzy_score(&c[..k], &c, scoring));
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// What the shodh binary prints and how it exits, for the behaviour that
+// lives in main rather than in the search itself
+
+use std::fs;
+use std::process::{Command, Output};
+
+// A fresh directory holding `files` (relative paths, parents created)
+fn fixture(files: &[&str]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for file in files {
+        let path = dir.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+    }
+    dir
+}
+
+fn shodh(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shodh")).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn nth_prints_only_that_result() {
+    let dir = fixture(&["main.rs", "mainframe", "my_ain.c"]);
+    let root = dir.path().to_str().unwrap();
+    let output = shodh(&["main", root, "--nth", "2"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("{}\n", dir.path().join("mainframe").display()));
+    // The filters apply first
+    let output = shodh(&["main", root, "--nth", "2", "--no-ext"]);
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("--nth 2 requested but only 1 results found"), "{}", stderr(&output));
+    let output = shodh(&["main", root, "--nth", "4"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("only 3 results found"), "{}", stderr(&output));
+}
+
+#[test]
+fn stats_json_has_the_stable_keys() {
+    let dir = fixture(&["main.rs", "mainframe", "other"]);
+    let output = shodh(&["main", dir.path().to_str().unwrap(), "--stats-json", "--format", "path"]);
+    let stats = stderr(&output);
+    let body = stats.trim().strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or_else(|| panic!("{}", stats));
+    let fields: Vec<(&str, f64)> = body.split(',')
+        .map(|field| {
+            let (key, value) = field.split_once(':').unwrap();
+            (key.trim_matches('"'), value.parse().unwrap())
+        })
+        .collect();
+    let keys: Vec<&str> = fields.iter().map(|(key, _)| *key).collect();
+    assert_eq!(keys, ["candidates", "matched", "shown", "walk_ms", "score_ms", "total_ms"]);
+    assert_eq!(fields[0].1, 3.0);
+    assert_eq!(fields[1].1, 2.0);
+    assert_eq!(fields[2].1, 2.0);
+    // Results stay on stdout, apart from the stats
+    assert_eq!(stdout(&output).lines().count(), 2);
+}
+
+#[test]
+fn limit_warning_needs_far_more_matches_than_shown() {
+    let names: Vec<String> = (0..25).map(|i| format!("main{}", i)).collect();
+    let dir = fixture(&names.iter().map(String::as_str).collect::<Vec<_>>());
+    let root = dir.path().to_str().unwrap();
+    // More than ten times --num
+    let hint = stderr(&shodh(&["main", root, "-n", "2"]));
+    assert!(hint.contains("25 matches; showing top 2"), "{}", hint);
+    assert_eq!(stderr(&shodh(&["main", root, "-n", "3"])), "");
+    assert_eq!(stderr(&shodh(&["main", root, "-n", "2", "--no-limit-warning"])), "");
+    assert_eq!(stderr(&shodh(&["main", root, "-n", "2", "--show-counts"])), "");
+}
+
+#[test]
+fn fail_if_ambiguous_rejects_near_ties() {
+    // main.c and main.h tie; main alone wins clearly
+    let dir = fixture(&["main.c", "main.h", "x/main"]);
+    let root = dir.path().to_str().unwrap();
+    let output = shodh(&["main.", root, "--fail-if-ambiguous", "--format", "path"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("ambiguous query"), "{}", stderr(&output));
+    let output = shodh(&["main", root, "--fail-if-ambiguous", "--format", "path"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).lines().next(), Some(dir.path().join("x/main").to_str().unwrap()));
+    // A wide enough margin makes even the clear winner ambiguous
+    let output = shodh(&["main", root, "--fail-if-ambiguous", "--ambiguity-margin", "3000", "--nth", "1"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn walk_max_entries_stops_the_walk_and_warns() {
+    let names: Vec<String> = (0..10).map(|i| format!("d{}/main", i)).collect();
+    let dir = fixture(&names.iter().map(String::as_str).collect::<Vec<_>>());
+    let root = dir.path().to_str().unwrap();
+    let output = shodh(&["main", root, "--walk-max-entries", "3", "--stats-json", "--format", "path"]);
+    let warning = stderr(&output);
+    assert!(warning.contains("stopped walking after 3 entries") && warning.contains("Narrow the root"), "{}", warning);
+    assert!(warning.contains("\"candidates\":3,"), "{}", warning);
+    assert!(stdout(&output).lines().count() <= 3);
+    let output = shodh(&["main", root, "--walk-max-entries", "20", "--format", "path"]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output).lines().count(), 10);
+}
+
+#[test]
+fn progress_json_stays_on_stderr() {
+    let names: Vec<String> = (0..500).map(|i| format!("d{}/f{}", i % 20, i)).collect();
+    let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+    names.push("main.rs");
+    let dir = fixture(&names);
+    let output = shodh(&["main", dir.path().to_str().unwrap(), "--progress-json", "--format", "path"]);
+    let progress = stderr(&output);
+    let lines: Vec<&str> = progress.lines().collect();
+    assert!(lines.iter().all(|l| l.starts_with("{\"scanned\":") && l.contains(",\"matched\":")), "{}", progress);
+    // Intermediate lines depend on timing, the last one always comes
+    assert_eq!(lines.last(), Some(&"{\"scanned\":521,\"matched\":1,\"done\":true}"));
+    assert_eq!(stdout(&output), format!("{}\n", dir.path().join("main.rs").display()));
+    // --scope-dir scores fewer entries than the walk found; only the stats say so
+    let output = shodh(&["main", dir.path().to_str().unwrap(), "--progress-json", "--stats-json", "--scope-dir", "d1"]);
+    let progress = stderr(&output);
+    assert!(progress.contains("{\"scanned\":521,\"matched\":0,\"done\":true}"), "{}", progress);
+    // d1 and d10 to d19, 25 files each
+    assert!(progress.contains("{\"candidates\":275,"), "{}", progress);
+}
+
+#[cfg(unix)]
+#[test]
+fn open_runs_the_editor_on_the_chosen_result() {
+    let dir = fixture(&["main.rs", "my main.c"]);
+    let root = dir.path().to_str().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let opened = out.path().join("opened");
+    let open = |args: &[&str], editor: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_shodh"));
+        command.args(args).env_remove("VISUAL").env_remove("EDITOR").env("OPENED", &opened);
+        if let Some(editor) = editor {
+            command.env("EDITOR", editor);
+        }
+        command.output().unwrap()
+    };
+    // The editor gets the quoted path as its one argument
+    let editor = "printf '%s\\n' >\"$OPENED\"";
+    assert!(open(&["main", root, "--open"], Some(editor)).status.success());
+    assert_eq!(fs::read_to_string(&opened).unwrap(), format!("{}\n", dir.path().join("main.rs").display()));
+    assert!(open(&["main", root, "--open", "--nth", "2"], Some(editor)).status.success());
+    assert_eq!(fs::read_to_string(&opened).unwrap(), format!("{}\n", dir.path().join("my main.c").display()));
+    let output = open(&["main", root, "--open"], None);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("--open needs $VISUAL or $EDITOR to be set"), "{}", stderr(&output));
+    let output = open(&["main", root, "--open", "--nth", "3"], Some(editor));
+    assert!(stderr(&output).contains("nothing to open"), "{}", stderr(&output));
+}
+
+#[cfg(unix)]
+#[test]
+fn pipe_streams_every_result_into_one_command() {
+    let dir = fixture(&["main.rs", "mainframe", "my_ain.c", "xyz"]);
+    let root = dir.path().to_str().unwrap();
+    let output = shodh(&["main", root, "--pipe", "wc -l"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).trim(), "3");
+    // A consumer that stops reading early isn't an error
+    let names: Vec<String> = (0..3000).map(|i| format!("main{}", i)).collect();
+    let dir = fixture(&names.iter().map(String::as_str).collect::<Vec<_>>());
+    let output = shodh(&["main", dir.path().to_str().unwrap(), "-n", "3000", "--pipe", "head -n 1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).lines().count(), 1);
+}
+
+#[test]
+fn why_above_marks_the_decisive_component() {
+    let dir = fixture(&["main", "mainframe", "my_ain"]);
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    let decisive = |a: &str, b: &str| {
+        let output = shodh(&["main", dir.path().to_str().unwrap(), "--why-above", &path(a), &path(b)]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let printed = stdout(&output);
+        assert!(printed.starts_with(&format!("{} ranks above {}", path(b), path(a))), "{}", printed);
+        let marked: Vec<&str> = printed.lines().filter(|line| line.contains("<- decisive")).collect();
+        assert_eq!(marked.len(), 1, "{}", printed);
+        marked[0].split_whitespace().next().unwrap().to_string()
+    };
+    // Given the lower-ranked path first, so the order is worked out too
+    assert_eq!(decisive("mainframe", "main"), "exact_boost");
+    assert_eq!(decisive("my_ain", "mainframe"), "prefix_boost");
+    // Styled like the results: plain under matched-only, basic codes only under --simple-color
+    let explain = |extra: &[&str]| {
+        stdout(&shodh(&[&["main", dir.path().to_str().unwrap(), "--why-above", &path("main"), &path("xyz")][..], extra].concat()))
+    };
+    let plain = explain(&["--theme", "matched-only"]);
+    assert!(plain.contains("<- decisive") && plain.contains("note: ") && !plain.contains('\x1b'), "{:?}", plain);
+    let simple = explain(&["--simple-color"]);
+    assert!(simple.contains("<- decisive") && !simple.contains("\x1b[1m") && !simple.contains("\x1b[2m"), "{:?}", simple);
+}
+
+#[test]
+fn nth_picks_the_row_show_rank_lists() {
+    let dir = fixture(&["x/main", "y/main", "mainframe", "boosted/my_ain", "maxin"]);
+    let root = dir.path().to_str().unwrap();
+    let cases: [&[&str]; 4] = [
+        &["--exact-first", "--boost-path", "boosted/:9000"],
+        &["--per-tier", "1"],
+        &["--display-min-score", "5000"],
+        &["--sample", "3", "--seed", "7"],
+    ];
+    for case in cases {
+        let args = [&["main", root, "--files-only"][..], case].concat();
+        let listed = stdout(&shodh(&[&args[..], &["--show-rank", "--theme", "matched-only"]].concat()))
+            .replace("\x1b[1m", "")
+            .replace("\x1b[0m", "");
+        let rows: Vec<&str> = listed.lines()
+            .filter_map(|line| line.split_once(". [")?.1.split_once("FILE  ").map(|(_, path)| path))
+            .collect();
+        assert!(!rows.is_empty(), "{}", listed);
+        for (i, row) in rows.iter().enumerate() {
+            let output = shodh(&[&args[..], &["--nth", &(i + 1).to_string()]].concat());
+            assert_eq!(stdout(&output), format!("{}\n", row), "{:?}", case);
+        }
+        let past = (rows.len() + 1).to_string();
+        let output = shodh(&[&args[..], &["--nth", &past]].concat());
+        assert_eq!(output.status.code(), Some(1), "{:?}", case);
+    }
+}